@@ -0,0 +1,139 @@
+//! Narrowing the field queries down to those affected by changes to the query
+//! file since a git ref, so that only the fields a change touches are timed.
+
+use std::{collections::BTreeSet, path::Path, process::Command};
+
+use anyhow::Context;
+
+use crate::parser::FieldQuery;
+
+/// Returns the lines of the file, as it is now, that differ from the file at
+/// the given ref. Where lines were only removed, the line before the removal
+/// is returned instead.
+pub(crate) fn changed_lines(file: &Path, base: &str) -> anyhow::Result<Vec<usize>> {
+    let dir = match file.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let name = file.file_name().context("the query file has no name")?;
+
+    let output = Command::new("git")
+        .args(["diff", "--no-color", "--no-ext-diff", "--unified=0"])
+        .arg(base)
+        .arg("--")
+        .arg(name)
+        .current_dir(dir)
+        .output()
+        .context("cannot run git")?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "git diff failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    parse_diff(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// Returns the changed lines of the new file from the hunk headers of a
+/// diff with no context lines.
+fn parse_diff(diff: &str) -> anyhow::Result<Vec<usize>> {
+    let mut lines = Vec::new();
+    for hunk in diff.lines().filter_map(|line| line.strip_prefix("@@ ")) {
+        // The header is "@@ -START[,COUNT] +START[,COUNT] @@".
+        let range = hunk
+            .split_whitespace()
+            .find_map(|range| range.strip_prefix('+'))
+            .with_context(|| format!("malformed hunk header: @@ {}", hunk))?;
+        let (start, count) = match range.split_once(',') {
+            Some((start, count)) => (start.parse::<usize>()?, count.parse::<usize>()?),
+            None => (range.parse::<usize>()?, 1),
+        };
+
+        if count == 0 {
+            lines.push(start.max(1));
+        } else {
+            lines.extend(start..start + count);
+        }
+    }
+
+    Ok(lines)
+}
+
+/// Keeps only the queries affected by the changed lines. Each changed line is
+/// attributed to the closest definition or selection starting at or before
+/// it, and a query is affected if that node is on its path.
+pub(crate) fn retain_changed(queries: &mut Vec<FieldQuery>, changed: &[usize]) {
+    let starts: BTreeSet<usize> = queries
+        .iter()
+        .flat_map(|query| query.lines.iter().copied())
+        .collect();
+    let owners: BTreeSet<usize> = changed
+        .iter()
+        .filter_map(|line| starts.range(..=line).next_back().copied())
+        .collect();
+
+    queries.retain(|query| query.lines.iter().any(|line| owners.contains(line)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field_query(lines: Vec<usize>) -> FieldQuery {
+        FieldQuery {
+            id: String::from("id"),
+            query: String::from("{ a }"),
+            path: vec![String::from("a")],
+            tags: Default::default(),
+            endpoint: None,
+            lines,
+            read_only: true,
+            arg_template: None,
+        }
+    }
+
+    #[test]
+    fn hunk_headers_give_changed_lines() {
+        let diff = "\
+diff --git a/q.graphql b/q.graphql
+--- a/q.graphql
++++ b/q.graphql
+@@ -3 +3 @@ query {
+-  a
++  b
+@@ -7,0 +8,2 @@
++  c
++  d
+@@ -12,2 +13,0 @@
+-  e
+-  f
+@@ -1 +0,0 @@
+-  g
+";
+        assert_eq!(parse_diff(diff).unwrap(), vec![3, 8, 9, 13, 1]);
+        assert_eq!(parse_diff("").unwrap(), Vec::<usize>::new());
+        assert!(parse_diff("@@ -1 +x @@").is_err());
+    }
+
+    #[test]
+    fn changed_lines_select_owning_queries() {
+        // Two fields under a shared operation on line 1, starting on lines 2
+        // and 5.
+        let queries = vec![field_query(vec![1, 2]), field_query(vec![1, 5])];
+        let retained = |changed: &[usize]| {
+            let mut retained = queries.clone();
+            retain_changed(&mut retained, changed);
+            retained
+                .into_iter()
+                .map(|query| query.lines)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(retained(&[3]), vec![vec![1, 2]]);
+        assert_eq!(retained(&[6, 7]), vec![vec![1, 5]]);
+        // A change to the operation affects every field under it.
+        assert_eq!(retained(&[1]), vec![vec![1, 2], vec![1, 5]]);
+        assert!(retained(&[]).is_empty());
+    }
+}
@@ -0,0 +1,180 @@
+//! Output of the query timings in the Chrome trace event format, for viewing
+//! as a timeline in chrome://tracing or Perfetto.
+
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+
+use crate::timer::{Result, Status};
+
+/// The samples of a run, each of which becomes a complete ("X") event. Events
+/// are placed by monotonic time, so adjustments to the wall clock during the
+/// run can't reorder them or give them negative offsets.
+#[derive(Debug)]
+pub(crate) struct ChromeTrace {
+    started: Instant,
+    samples: Vec<Sample>,
+}
+
+#[derive(Debug)]
+struct Sample {
+    // Offset from the start of the run.
+    start: Duration,
+    duration: Duration,
+    args: Value,
+    category: String,
+    name: String,
+}
+
+impl ChromeTrace {
+    pub(crate) fn start() -> Self {
+        Self {
+            started: Instant::now(),
+            samples: Vec::new(),
+        }
+    }
+
+    /// Adds an event for a single sample of a query. Results without a start
+    /// time are ignored, since the event can't be placed.
+    pub(crate) fn add(&mut self, result: &Result) {
+        let start = match result.offset(self.started) {
+            Some(start) => start,
+            None => return,
+        };
+
+        self.samples.push(Sample {
+            start,
+            duration: result.duration,
+            args: json!({
+                "id": result.query.id,
+                "status": match result.status {
+                    Status::Success => "ok",
+                    Status::Failure => "error",
+                },
+                "status_code": result.status_code,
+                "error_code": result.error_code,
+            }),
+            category: result.query.path.first().cloned().unwrap_or_default(),
+            name: result.query.path.join("."),
+        });
+    }
+
+    /// Writes the trace as a JSON object. Samples are laid out on as few
+    /// tracks as possible without overlapping, so queries in flight at the
+    /// same time appear on separate tracks.
+    pub(crate) fn write(self, pretty: bool) -> anyhow::Result<()> {
+        let trace = self.trace();
+        println!(
+            "{}",
+            if pretty {
+                serde_json::to_string_pretty(&trace)?
+            } else {
+                serde_json::to_string(&trace)?
+            }
+        );
+        Ok(())
+    }
+
+    fn trace(mut self) -> Value {
+        self.samples.sort_by_key(|sample| sample.start);
+
+        // The time at which the last sample on each track ends.
+        let mut tracks: Vec<Duration> = Vec::new();
+        let mut events = Vec::new();
+        for sample in self.samples.into_iter() {
+            let track = match tracks.iter().position(|end| *end <= sample.start) {
+                Some(track) => track,
+                None => {
+                    tracks.push(Duration::ZERO);
+                    tracks.len() - 1
+                }
+            };
+            tracks[track] = sample.start + sample.duration;
+
+            events.push(json!({
+                "name": sample.name,
+                "cat": sample.category,
+                "ph": "X",
+                "ts": micros(sample.start),
+                "dur": micros(sample.duration),
+                "pid": 1,
+                "tid": track + 1,
+                "args": sample.args,
+            }));
+        }
+        events.extend((0..tracks.len()).map(|track| {
+            json!({
+                "name": "thread_name",
+                "ph": "M",
+                "pid": 1,
+                "tid": track + 1,
+                "args": { "name": format!("track {}", track + 1) },
+            })
+        }));
+
+        json!({ "traceEvents": events, "displayTimeUnit": "ms" })
+    }
+}
+
+/// Timestamps and durations in trace events are in microseconds.
+fn micros(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1_000_000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(name: &str, start: u64, duration: u64) -> Sample {
+        Sample {
+            start: Duration::from_millis(start),
+            duration: Duration::from_millis(duration),
+            args: Value::Null,
+            category: String::new(),
+            name: name.to_string(),
+        }
+    }
+
+    #[test]
+    fn overlapping_samples_get_separate_tracks() {
+        let trace = ChromeTrace {
+            started: Instant::now(),
+            // Out of order, to show that samples are placed by start time.
+            samples: vec![
+                sample("c", 15, 10),
+                sample("a", 0, 20),
+                sample("b", 5, 10),
+                sample("d", 20, 5),
+            ],
+        }
+        .trace();
+
+        let tracks = trace["traceEvents"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|event| {
+                (
+                    event["ph"].as_str().unwrap(),
+                    event["name"].as_str().unwrap(),
+                    event["tid"].as_u64().unwrap(),
+                )
+            })
+            .collect::<Vec<_>>();
+        // b overlaps a, so starts a second track; c starts once b has ended,
+        // and d once a has.
+        assert_eq!(
+            tracks,
+            vec![
+                ("X", "a", 1),
+                ("X", "b", 2),
+                ("X", "c", 2),
+                ("X", "d", 1),
+                ("M", "thread_name", 1),
+                ("M", "thread_name", 2),
+            ]
+        );
+        assert_eq!(trace["traceEvents"][1]["ts"], 5000.0);
+        assert_eq!(trace["traceEvents"][1]["dur"], 10000.0);
+    }
+}
@@ -0,0 +1,250 @@
+//! Comparison with the results of earlier runs, saved with `--format json`.
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+};
+
+use anyhow::Context;
+use console::style;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::output::{Format, OutputOptions};
+
+/// The parts of a saved result needed to compare it.
+#[derive(Debug, Deserialize)]
+struct Saved {
+    id: String,
+    path: Vec<String>,
+    duration: f64,
+    status: String,
+}
+
+/// The change in a field's duration between the two runs.
+#[derive(Debug, Serialize)]
+struct Delta {
+    id: String,
+    path: String,
+    before: f64,
+    after: f64,
+    delta: f64,
+    /// Whether the field's status differs between the runs.
+    status_changed: bool,
+}
+
+fn load(path: &Path) -> anyhow::Result<Vec<Saved>> {
+    let raw = std::fs::read_to_string(path)
+        .with_context(|| format!("reading results file {}", path.display()))?;
+    serde_json::from_str(&raw).with_context(|| {
+        format!(
+            "parsing results file {}; it should be the output of --format json",
+            path.display()
+        )
+    })
+}
+
+/// Returns the ids of the queries that took longer than the threshold, in
+/// seconds, in an earlier run.
+pub(crate) fn slow_ids(path: &Path, threshold: f64) -> anyhow::Result<HashSet<String>> {
+    Ok(load(path)?
+        .into_iter()
+        .filter(|saved| saved.duration > threshold)
+        .map(|saved| saved.id)
+        .collect())
+}
+
+/// The fields of two runs joined by query id.
+#[derive(Debug)]
+struct Comparison {
+    /// Fields in both runs, largest regression first.
+    deltas: Vec<Delta>,
+    only_before: usize,
+    only_after: usize,
+}
+
+/// Joins the results of two runs by query id. If a run has more than one
+/// result for an id, the last is used, so that each field is only counted
+/// once.
+fn join(before: Vec<Saved>, after: Vec<Saved>) -> Comparison {
+    let by_id = |saved: Vec<Saved>| -> HashMap<String, Saved> {
+        saved
+            .into_iter()
+            .map(|saved| (saved.id.clone(), saved))
+            .collect()
+    };
+    let before = by_id(before);
+    let after = by_id(after);
+
+    let mut deltas: Vec<Delta> = before
+        .values()
+        .filter_map(|saved| {
+            after.get(&saved.id).map(|other| Delta {
+                id: saved.id.clone(),
+                path: saved.path.join("."),
+                before: saved.duration,
+                after: other.duration,
+                delta: other.duration - saved.duration,
+                status_changed: saved.status != other.status,
+            })
+        })
+        .collect();
+    deltas.sort_by(|a, b| {
+        b.delta
+            .total_cmp(&a.delta)
+            .then_with(|| a.path.cmp(&b.path))
+    });
+
+    Comparison {
+        only_before: before.len() - deltas.len(),
+        only_after: after.len() - deltas.len(),
+        deltas,
+    }
+}
+
+/// Joins the results of two runs by query id and writes the change in each
+/// field's duration, largest regression first, followed by a summary.
+pub(crate) fn compare(options: &OutputOptions, before: &Path, after: &Path) -> anyhow::Result<()> {
+    let Comparison {
+        deltas,
+        only_before: removed,
+        only_after: added,
+    } = join(load(before)?, load(after)?);
+
+    let slower = deltas.iter().filter(|d| d.delta > 0.0).count();
+    let faster = deltas.iter().filter(|d| d.delta < 0.0).count();
+    let net = deltas.iter().fold(0.0, |net, d| net + d.delta);
+
+    match options.format {
+        Format::Human => {
+            let width = deltas.iter().map(|d| d.path.len()).max().unwrap_or(0);
+            for delta in deltas.iter() {
+                let change = format!(
+                    "{:+.3}s{}",
+                    delta.delta,
+                    match delta.before {
+                        before if before > 0.0 =>
+                            format!(" ({:+.0}%)", delta.delta / before * 100.0),
+                        _ => String::new(),
+                    }
+                );
+                println!(
+                    "{:width$}  {:.3}s -> {:.3}s  {}{}",
+                    delta.path,
+                    delta.before,
+                    delta.after,
+                    if delta.delta > 0.0 {
+                        style(change).red()
+                    } else if delta.delta < 0.0 {
+                        style(change).green()
+                    } else {
+                        style(change).dim()
+                    },
+                    if delta.status_changed {
+                        format!(" {}", style("status changed").yellow())
+                    } else {
+                        String::new()
+                    },
+                    width = width
+                );
+            }
+            println!(
+                "{}",
+                style(format!(
+                    "{} fields compared: {} slower, {} faster; net change {:+.3}s",
+                    deltas.len(),
+                    slower,
+                    faster,
+                    net
+                ))
+                .bold()
+            );
+            if added > 0 || removed > 0 {
+                println!(
+                    "{}",
+                    style(format!(
+                        "{} fields only in the first run, {} only in the second",
+                        removed, added
+                    ))
+                    .dim()
+                );
+            }
+        }
+        Format::Json | Format::ChromeTrace => {
+            let value = json!({
+                "fields": deltas,
+                "slower": slower,
+                "faster": faster,
+                "net": net,
+                "only_before": removed,
+                "only_after": added,
+            });
+            println!(
+                "{}",
+                if options.json_pretty {
+                    serde_json::to_string_pretty(&value)?
+                } else {
+                    serde_json::to_string(&value)?
+                }
+            );
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn saved(id: &str, duration: f64, status: &str) -> Saved {
+        Saved {
+            id: id.to_string(),
+            path: vec![id.to_string()],
+            duration,
+            status: status.to_string(),
+        }
+    }
+
+    #[test]
+    fn joins_by_id() {
+        let comparison = join(
+            vec![
+                saved("a", 1.0, "success"),
+                saved("b", 2.0, "success"),
+                saved("c", 1.0, "success"),
+            ],
+            vec![
+                saved("b", 1.5, "failure"),
+                saved("a", 1.5, "success"),
+                saved("d", 1.0, "success"),
+            ],
+        );
+
+        let summary: Vec<_> = comparison
+            .deltas
+            .iter()
+            .map(|d| (d.id.as_str(), d.delta, d.status_changed))
+            .collect();
+        assert_eq!(summary, [("a", 0.5, false), ("b", -0.5, true)]);
+        assert_eq!(comparison.only_before, 1);
+        assert_eq!(comparison.only_after, 1);
+    }
+
+    #[test]
+    fn duplicate_ids_are_counted_once() {
+        let comparison = join(
+            vec![
+                saved("a", 1.0, "success"),
+                saved("a", 2.0, "success"),
+                saved("b", 1.0, "success"),
+            ],
+            vec![saved("a", 3.0, "success"), saved("a", 3.0, "success")],
+        );
+
+        assert_eq!(comparison.deltas.len(), 1);
+        assert_eq!(comparison.deltas[0].before, 2.0);
+        assert_eq!(comparison.only_before, 1);
+        assert_eq!(comparison.only_after, 0);
+    }
+}
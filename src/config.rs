@@ -0,0 +1,209 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use serde::Deserialize;
+use serde_json::Value;
+
+/// The configuration file looked for in the current directory when `--config`
+/// isn't given.
+const DEFAULT_PATH: &str = "graphql-field-timer.toml";
+
+/// Defaults loaded from a TOML configuration file.
+///
+/// Values in the file have the lowest precedence: anything given on the
+/// command line or through the environment wins.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Config {
+    /// Profile applied when `--profile` isn't given.
+    pub(crate) default_profile: Option<String>,
+    pub(crate) header: Vec<String>,
+    pub(crate) profiles: BTreeMap<String, Profile>,
+    /// Seconds to wait for each query, as with `--timeout`.
+    pub(crate) timeout: Option<f64>,
+    pub(crate) url: Option<String>,
+    pub(crate) variables: Option<Value>,
+}
+
+/// A named set of values, such as for a particular environment, that take
+/// precedence over the top level values in the file when selected.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Profile {
+    pub(crate) header: Vec<String>,
+    pub(crate) timeout: Option<f64>,
+    pub(crate) url: Option<String>,
+    pub(crate) variables: Option<Value>,
+}
+
+impl Config {
+    /// Loads the configuration from the given path, or from the default path
+    /// if it exists. A missing default file results in an empty
+    /// configuration; a missing explicit file is an error.
+    pub(crate) fn load(path: Option<&Path>) -> anyhow::Result<Self> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => {
+                let path = PathBuf::from(DEFAULT_PATH);
+                if !path.exists() {
+                    return Ok(Self::default());
+                }
+                path
+            }
+        };
+
+        let raw = std::fs::read_to_string(&path)
+            .with_context(|| format!("reading config file {}", path.display()))?;
+        toml::from_str(&raw).with_context(|| format!("parsing config file {}", path.display()))
+    }
+
+    /// Applies the named profile, or the default profile if no name is given,
+    /// over the top level values. Headers in the profile replace top level
+    /// headers with the same name.
+    pub(crate) fn select_profile(mut self, name: Option<&str>) -> anyhow::Result<Self> {
+        let name = match name.or(self.default_profile.as_deref()) {
+            Some(name) => name.to_string(),
+            None => return Ok(self),
+        };
+        let profile = match self.profiles.remove(&name) {
+            Some(profile) => profile,
+            None => anyhow::bail!("no profile named {} in the config file", name),
+        };
+
+        self.header = self.merge_headers(profile.header);
+        self.timeout = profile.timeout.or(self.timeout);
+        self.url = profile.url.or(self.url);
+        self.variables = profile.variables.or(self.variables);
+        Ok(self)
+    }
+
+    /// Merges the headers from the configuration file with those given on the
+    /// command line. Command line headers replace any file headers with the
+    /// same name.
+    pub(crate) fn merge_headers(&self, headers: Vec<String>) -> Vec<String> {
+        let overridden: Vec<String> = headers.iter().filter_map(|h| header_name(h)).collect();
+
+        self.header
+            .iter()
+            .filter(|header| match header_name(header) {
+                Some(name) => !overridden.contains(&name),
+                None => true,
+            })
+            .cloned()
+            .chain(headers)
+            .collect()
+    }
+
+    /// Returns the variables as a JSON string, if any were configured. The
+    /// variables may be given either as a TOML table or as a JSON string.
+    pub(crate) fn variables(&self) -> Option<String> {
+        self.variables.as_ref().map(|variables| match variables {
+            Value::String(json) => json.clone(),
+            variables => variables.to_string(),
+        })
+    }
+}
+
+fn header_name(header: &str) -> Option<String> {
+    header
+        .split_once(':')
+        .map(|(name, _)| name.trim().to_ascii_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    /// Writes the configuration to a file of its own and loads it.
+    fn load(name: &str, raw: &str) -> anyhow::Result<Config> {
+        let path = std::env::temp_dir().join(format!(
+            "graphql-field-timer-{}-{}.toml",
+            name,
+            std::process::id()
+        ));
+        std::fs::write(&path, raw).unwrap();
+        let config = Config::load(Some(&path));
+        std::fs::remove_file(&path).unwrap();
+        config
+    }
+
+    const RAW: &str = r#"
+        default_profile = "staging"
+        header = ["Authorization: Bearer file", "X-Env: top"]
+        timeout = 5
+        url = "http://localhost/graphql"
+        variables = { id = 1 }
+
+        [profiles.staging]
+        header = ["x-env: staging"]
+        url = "https://staging.example.com/graphql"
+
+        [profiles.prod]
+        timeout = 2.5
+        variables = '{"id": 2}'
+    "#;
+
+    #[test]
+    fn loads_top_level_values() {
+        let config = load("top", RAW).unwrap().select_profile(None).unwrap();
+        assert_eq!(config.timeout, Some(5.0));
+        assert_eq!(
+            config.url.as_deref(),
+            Some("https://staging.example.com/graphql")
+        );
+        assert_eq!(config.variables(), Some(json!({"id": 1}).to_string()));
+        assert_eq!(
+            config.header,
+            ["Authorization: Bearer file", "x-env: staging"]
+        );
+    }
+
+    #[test]
+    fn named_profile_overrides_default_profile() {
+        let config = load("named", RAW)
+            .unwrap()
+            .select_profile(Some("prod"))
+            .unwrap();
+        assert_eq!(config.timeout, Some(2.5));
+        assert_eq!(config.url.as_deref(), Some("http://localhost/graphql"));
+        assert_eq!(config.variables().as_deref(), Some(r#"{"id": 2}"#));
+        assert_eq!(config.header, ["Authorization: Bearer file", "X-Env: top"]);
+    }
+
+    #[test]
+    fn unknown_profile_is_an_error() {
+        let config = load("unknown-profile", RAW).unwrap();
+        assert!(config.select_profile(Some("dev")).is_err());
+    }
+
+    #[test]
+    fn unknown_field_is_an_error() {
+        assert!(load("unknown-field", "retries = 3").is_err());
+    }
+
+    #[test]
+    fn missing_explicit_file_is_an_error() {
+        let path = std::env::temp_dir().join("graphql-field-timer-missing.toml");
+        assert!(Config::load(Some(&path)).is_err());
+    }
+
+    #[test]
+    fn command_line_headers_replace_file_headers() {
+        let config = Config {
+            header: vec![
+                String::from("Authorization: Bearer file"),
+                String::from("X-Env: top"),
+            ],
+            ..Config::default()
+        };
+        assert_eq!(
+            config.merge_headers(vec![String::from("authorization: Bearer cli")]),
+            ["X-Env: top", "authorization: Bearer cli"]
+        );
+    }
+}
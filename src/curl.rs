@@ -0,0 +1,88 @@
+//! Rendering requests as curl commands, so that a query can be reproduced
+//! without this tool.
+
+use hyper::{header::CONTENT_LENGTH, HeaderMap, Uri};
+use itertools::Itertools;
+
+/// Builds a curl command that sends the same request, one option per line.
+/// Addresses the host should resolve to are passed with curl's own --resolve,
+/// in its HOST:PORT:ADDR form.
+pub(crate) fn command(uri: &Uri, headers: &HeaderMap, body: &[u8], resolve: &[String]) -> String {
+    let mut args = vec![format!("curl {}", quote(&uri.to_string()))];
+    args.extend(
+        resolve
+            .iter()
+            .map(|resolve| format!("--resolve {}", quote(resolve))),
+    );
+    // curl works out the length itself, and would send a second
+    // Content-Length header if given one.
+    args.extend(
+        headers
+            .iter()
+            .filter(|(name, _)| **name != CONTENT_LENGTH)
+            .map(|(name, value)| {
+                format!(
+                    "-H {}",
+                    quote(&format!(
+                        "{}: {}",
+                        name,
+                        String::from_utf8_lossy(value.as_bytes())
+                    ))
+                )
+            }),
+    );
+    args.push(format!(
+        "--data-raw {}",
+        quote(&String::from_utf8_lossy(body))
+    ));
+
+    args.iter().join(" \\\n  ")
+}
+
+/// Quotes a string for a POSIX shell. Single quotes can't be escaped within
+/// single quotes, so each one closes the string, adds an escaped quote, and
+/// reopens it.
+fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::header::{HeaderValue, CONTENT_TYPE};
+
+    use super::*;
+
+    #[test]
+    fn quote_escapes_single_quotes() {
+        assert_eq!(quote("plain"), "'plain'");
+        assert_eq!(quote("it's"), r"'it'\''s'");
+        assert_eq!(quote("''"), r"''\'''\'''");
+    }
+
+    #[test]
+    fn command_quotes_every_argument() {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        headers.insert(CONTENT_LENGTH, HeaderValue::from_static("42"));
+        headers.insert("x-note", HeaderValue::from_static("don't"));
+        let uri = Uri::from_static("https://example.com/graphql");
+        let body = br#"{"query":"{ user(name: \"O'Brien\") { id } }"}"#;
+
+        assert_eq!(
+            command(
+                &uri,
+                &headers,
+                body,
+                &[String::from("example.com:443:127.0.0.1")]
+            ),
+            [
+                "curl 'https://example.com/graphql' \\",
+                "  --resolve 'example.com:443:127.0.0.1' \\",
+                "  -H 'content-type: application/json' \\",
+                r"  -H 'x-note: don'\''t' \",
+                r#"  --data-raw '{"query":"{ user(name: \"O'\''Brien\") { id } }"}'"#,
+            ]
+            .join("\n")
+        );
+    }
+}
@@ -0,0 +1,184 @@
+//! Support for reporting the timings recorded in a HAR capture, rather than
+//! sending the queries.
+
+use std::{collections::HashMap, path::Path, time::Duration};
+
+use anyhow::Context;
+use itertools::Itertools;
+use serde::Deserialize;
+
+use crate::{
+    parser::FieldQuery,
+    timer::{self, FailureOrder, Result},
+};
+
+#[derive(Debug, Deserialize)]
+struct File {
+    log: Log,
+}
+
+#[derive(Debug, Deserialize)]
+struct Log {
+    entries: Vec<Entry>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Entry {
+    request: EntryRequest,
+    response: EntryResponse,
+    time: f64,
+    timings: Timings,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct EntryRequest {
+    post_data: Option<PostData>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PostData {
+    text: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct EntryResponse {
+    status: u16,
+    content: Content,
+}
+
+#[derive(Debug, Deserialize)]
+struct Content {
+    text: Option<String>,
+}
+
+/// Phase timings in milliseconds. Phases that don't apply are recorded as -1.
+#[derive(Debug, Deserialize)]
+struct Timings {
+    connect: f64,
+    receive: f64,
+    send: f64,
+    wait: f64,
+}
+
+#[derive(Debug, Deserialize)]
+struct GraphQLRequest {
+    query: String,
+}
+
+/// The GraphQL requests in a HAR capture, indexed by their normalised query
+/// text.
+#[derive(Debug)]
+pub(crate) struct Har {
+    entries: HashMap<String, Vec<Entry>>,
+}
+
+impl Har {
+    pub(crate) fn load(path: &Path) -> anyhow::Result<Self> {
+        let raw = std::fs::read_to_string(path)
+            .with_context(|| format!("reading HAR file {}", path.display()))?;
+        let file: File = serde_json::from_str(&raw)
+            .with_context(|| format!("parsing HAR file {}", path.display()))?;
+
+        // Anything that isn't a single GraphQL query sent as JSON in a POST
+        // body, such as a batch or a GET request, is ignored.
+        let mut entries: HashMap<String, Vec<Entry>> = HashMap::new();
+        for entry in file.log.entries.into_iter() {
+            let text = match entry
+                .request
+                .post_data
+                .as_ref()
+                .and_then(|d| d.text.as_ref())
+            {
+                Some(text) => text,
+                None => continue,
+            };
+            if let Ok(request) = serde_json::from_str::<GraphQLRequest>(text) {
+                entries
+                    .entry(normalise(&request.query))
+                    .or_default()
+                    .push(entry);
+            }
+        }
+
+        Ok(Self { entries })
+    }
+
+    /// Builds a result for each query from the matching recorded requests,
+    /// along with the queries that had no match. Each matching request is
+    /// treated as a sample of the query.
+    pub(crate) fn results<'a>(
+        &self,
+        queries: &'a [FieldQuery],
+        failures: FailureOrder,
+    ) -> anyhow::Result<(Vec<Result>, Vec<&'a FieldQuery>)> {
+        let mut results = Vec::new();
+        let mut unmatched = Vec::new();
+
+        for query in queries.iter() {
+            let entries = match self.entries.get(&normalise(&query.query)) {
+                Some(entries) => entries,
+                None => {
+                    unmatched.push(query);
+                    continue;
+                }
+            };
+
+            let mut result: Option<Result> = None;
+            for entry in entries.iter() {
+                let body = entry.response.content.text.as_deref().with_context(|| {
+                    format!(
+                        "the response to {} was not recorded in the HAR file",
+                        query.path.join(".")
+                    )
+                })?;
+                let sample = Result::recorded(
+                    query,
+                    entry.duration(),
+                    entry.received(),
+                    // No time is spent connecting when a connection is reused.
+                    entry.timings.connect < 0.0,
+                    entry.response.status,
+                    body,
+                )?;
+                match result.as_mut() {
+                    Some(result) => result.add_sample(sample),
+                    None => result = Some(sample),
+                }
+            }
+            results.extend(result);
+        }
+
+        timer::sort_results(&mut results, failures);
+        Ok((results, unmatched))
+    }
+}
+
+impl Entry {
+    /// Returns the time taken to receive the response headers, to match what
+    /// the timer measures, falling back to the total time if the phases
+    /// weren't recorded.
+    fn duration(&self) -> Duration {
+        let millis = if self.timings.send >= 0.0 && self.timings.wait >= 0.0 {
+            self.timings.send + self.timings.wait
+        } else {
+            self.time
+        };
+        Duration::from_secs_f64(millis.max(0.0) / 1000.0)
+    }
+
+    /// Returns the time taken to receive the full response body.
+    fn received(&self) -> Duration {
+        self.duration() + Duration::from_secs_f64(self.timings.receive.max(0.0) / 1000.0)
+    }
+}
+
+/// Normalises a query so that formatting differences don't prevent a match.
+/// Queries that can't be parsed have their whitespace collapsed instead.
+fn normalise(query: &str) -> String {
+    match graphql_parser::parse_query::<&str>(query) {
+        Ok(doc) => doc.to_string(),
+        Err(_) => query.split_whitespace().join(" "),
+    }
+}
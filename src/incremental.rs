@@ -0,0 +1,210 @@
+//! Support for reading responses delivered incrementally as `multipart/mixed`,
+//! as servers do for queries using `@defer` or `@stream`.
+
+use std::time::Instant;
+
+use hyper::{body::HttpBody, Body};
+use serde_json::Value;
+
+/// The Accept header sent with each request, allowing the server to respond
+/// incrementally.
+pub(crate) const ACCEPT: &str = "multipart/mixed;deferSpec=20220824, application/json";
+
+/// A single part of an incremental response.
+#[derive(Debug)]
+pub(crate) struct Part {
+    pub(crate) payload: Value,
+    pub(crate) received: Instant,
+}
+
+/// Returns the multipart boundary if the content type indicates an incremental
+/// response.
+pub(crate) fn boundary(content_type: &str) -> Option<String> {
+    let mut params = content_type.split(';').map(str::trim);
+    if !params.next()?.eq_ignore_ascii_case("multipart/mixed") {
+        return None;
+    }
+
+    Some(
+        params
+            .find_map(|param| param.strip_prefix("boundary="))
+            .map(|boundary| boundary.trim_matches('"').to_string())
+            // The incremental delivery RFC uses a boundary of "-" by default.
+            .unwrap_or_else(|| String::from("-")),
+    )
+}
+
+/// Reads each part of a multipart response as it arrives, recording the time
+/// it was received. The total size of the body in bytes is also returned.
+pub(crate) async fn read_parts(
+    body: &mut Body,
+    boundary: &str,
+) -> anyhow::Result<(Vec<Part>, usize)> {
+    // Per RFC 2046, a delimiter is a boundary at the start of a line, so the
+    // buffer starts with a line break to match one at the start of the body.
+    let delimiter = format!("\r\n--{}", boundary).into_bytes();
+    let mut buf = b"\r\n".to_vec();
+    let mut parts = Vec::new();
+    let mut size = 0;
+    let mut closed = false;
+
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        size += chunk.len();
+        if closed {
+            // Anything after the close delimiter is an epilogue to ignore.
+            continue;
+        }
+        buf.extend_from_slice(&chunk);
+        let received = Instant::now();
+
+        loop {
+            match take_part(&mut buf, &delimiter) {
+                Next::Part(raw) => {
+                    if let Some(payload) = part_payload(&raw) {
+                        parts.push(Part {
+                            payload: serde_json::from_slice(payload)?,
+                            received,
+                        });
+                    }
+                }
+                Next::Close => {
+                    closed = true;
+                    break;
+                }
+                Next::Incomplete => break,
+            }
+        }
+    }
+
+    Ok((parts, size))
+}
+
+/// What the buffer holds after its first delimiter.
+#[derive(Debug, PartialEq)]
+enum Next {
+    /// A complete part, followed by another delimiter.
+    Part(Vec<u8>),
+    /// The close delimiter, after which there are no more parts.
+    Close,
+    /// More of the body is needed to tell.
+    Incomplete,
+}
+
+/// Removes the first complete part from the buffer, if there is one. A part is
+/// complete once the delimiter that follows it has been received. Anything
+/// before the first delimiter is a preamble, and is skipped.
+fn take_part(buf: &mut Vec<u8>, delimiter: &[u8]) -> Next {
+    let start = match find(buf, delimiter) {
+        Some(i) => i + delimiter.len(),
+        None => return Next::Incomplete,
+    };
+    match buf.get(start..start + 2) {
+        Some(b"--") => return Next::Close,
+        Some(_) => {}
+        None => return Next::Incomplete,
+    }
+    let end = match find(&buf[start..], delimiter) {
+        Some(i) => start + i,
+        None => return Next::Incomplete,
+    };
+
+    let part = buf[start..end].to_vec();
+    buf.drain(..end);
+    Next::Part(part)
+}
+
+/// Returns the body of a part, skipping over any part headers.
+fn part_payload(part: &[u8]) -> Option<&[u8]> {
+    let payload = match find(part, b"\r\n\r\n") {
+        Some(i) => &part[i + 4..],
+        None => part,
+    };
+    let payload = payload.trim_ascii();
+
+    if payload.is_empty() {
+        None
+    } else {
+        Some(payload)
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DELIMITER: &[u8] = b"\r\n---";
+
+    fn payloads(body: &[u8]) -> (Vec<String>, Next) {
+        let mut buf = b"\r\n".to_vec();
+        buf.extend_from_slice(body);
+        let mut payloads = Vec::new();
+        loop {
+            match take_part(&mut buf, DELIMITER) {
+                Next::Part(raw) => payloads.extend(
+                    part_payload(&raw).map(|payload| String::from_utf8_lossy(payload).into_owned()),
+                ),
+                next => return (payloads, next),
+            }
+        }
+    }
+
+    #[test]
+    fn boundary_from_content_type() {
+        assert_eq!(
+            boundary("multipart/mixed; boundary=\"graphql\"; deferSpec=20220824").as_deref(),
+            Some("graphql")
+        );
+        assert_eq!(boundary("Multipart/Mixed").as_deref(), Some("-"));
+        assert_eq!(boundary("application/json"), None);
+    }
+
+    #[test]
+    fn parts_with_and_without_headers() {
+        let (payloads, next) = payloads(
+            b"\r\n---\r\nContent-Type: application/json\r\n\r\n{\"data\":{}}\r\n\
+              ---\r\n\r\n{\"hasNext\":false}\r\n-----\r\n",
+        );
+        assert_eq!(payloads, [r#"{"data":{}}"#, r#"{"hasNext":false}"#]);
+        assert_eq!(next, Next::Close);
+    }
+
+    #[test]
+    fn payload_containing_the_boundary_is_not_split() {
+        let (payloads, next) =
+            payloads(b"---\r\n\r\n{\"data\":{\"rule\":\"---\",\"x\":\"a---b\"}}\r\n-----");
+        assert_eq!(payloads, [r#"{"data":{"rule":"---","x":"a---b"}}"#]);
+        assert_eq!(next, Next::Close);
+    }
+
+    #[test]
+    fn preamble_and_epilogue_are_ignored() {
+        let (payloads, next) =
+            payloads(b"preamble\r\n---\r\n\r\n{}\r\n-----\r\nepilogue\r\n---\r\n\r\n[]\r\n---");
+        assert_eq!(payloads, ["{}"]);
+        assert_eq!(next, Next::Close);
+    }
+
+    #[test]
+    fn incomplete_parts_wait_for_the_next_delimiter() {
+        let mut buf = b"\r\n---\r\n\r\n{\"data\"".to_vec();
+        assert_eq!(take_part(&mut buf, DELIMITER), Next::Incomplete);
+        buf.extend_from_slice(b":{}}\r\n-");
+        assert_eq!(take_part(&mut buf, DELIMITER), Next::Incomplete);
+        buf.extend_from_slice(b"--");
+        // The delimiter has arrived, but not whether it closes the body.
+        match take_part(&mut buf, DELIMITER) {
+            Next::Part(raw) => assert_eq!(part_payload(&raw), Some(&br#"{"data":{}}"#[..])),
+            next => panic!("expected a part, got {:?}", next),
+        }
+        assert_eq!(take_part(&mut buf, DELIMITER), Next::Incomplete);
+        buf.extend_from_slice(b"--\r\n");
+        assert_eq!(take_part(&mut buf, DELIMITER), Next::Close);
+    }
+}
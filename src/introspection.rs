@@ -0,0 +1,602 @@
+use std::{collections::HashMap, fmt::Display};
+
+use graphql_parser::query::{
+    Definition, Field, OperationDefinition, Selection, SelectionSet, TypeCondition, Value,
+};
+use serde::Deserialize;
+
+use crate::{parser::FieldQuery, timer::Timer};
+
+const INTROSPECTION_QUERY: &str = r#"
+query IntrospectionQuery {
+  __schema {
+    queryType { name }
+    types {
+      kind
+      name
+      fields(includeDeprecated: true) {
+        name
+        args {
+          name
+          defaultValue
+          type { ...TypeRef }
+        }
+        type { ...TypeRef }
+      }
+    }
+  }
+}
+
+fragment TypeRef on __Type {
+  kind
+  name
+  ofType {
+    kind
+    name
+    ofType {
+      kind
+      name
+      ofType {
+        kind
+        name
+        ofType {
+          kind
+          name
+        }
+      }
+    }
+  }
+}
+"#;
+
+#[derive(Debug, Deserialize)]
+struct IntrospectionResponse {
+    #[serde(rename = "__schema")]
+    schema: Schema,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Schema {
+    query_type: NamedType,
+    types: Vec<FullType>,
+}
+
+#[derive(Debug, Deserialize)]
+struct NamedType {
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct FullType {
+    kind: String,
+    name: String,
+    fields: Option<Vec<FieldDefinition>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FieldDefinition {
+    name: String,
+    args: Vec<InputValue>,
+    #[serde(rename = "type")]
+    field_type: TypeRef,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct InputValue {
+    name: String,
+    default_value: Option<String>,
+    #[serde(rename = "type")]
+    input_type: TypeRef,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TypeRef {
+    kind: String,
+    name: Option<String>,
+    of_type: Option<Box<TypeRef>>,
+}
+
+impl TypeRef {
+    /// Returns the named type at the bottom of any list and non-null
+    /// wrappers.
+    fn named(&self) -> Option<&str> {
+        match &self.of_type {
+            Some(of_type) => of_type.named(),
+            None => self.name.as_deref(),
+        }
+    }
+}
+
+impl Display for TypeRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.kind.as_str(), &self.of_type) {
+            ("NON_NULL", Some(of_type)) => write!(f, "{}!", of_type),
+            ("LIST", Some(of_type)) => write!(f, "[{}]", of_type),
+            _ => write!(f, "{}", self.name.as_deref().unwrap_or("?")),
+        }
+    }
+}
+
+impl Schema {
+    /// Fetches the schema from the endpoint the timer is configured for.
+    pub(crate) async fn fetch(timer: &Timer) -> anyhow::Result<Self> {
+        let response: IntrospectionResponse =
+            serde_json::from_value(timer.fetch(INTROSPECTION_QUERY).await?)?;
+        Ok(response.schema)
+    }
+
+    /// Synthesises a query selecting every field reachable from the query
+    /// type, up to the given depth.
+    ///
+    /// Fields with required arguments are skipped, since we have no way of
+    /// knowing what values to provide, as are fields that would revisit a
+    /// type already being selected further up the tree.
+    pub(crate) fn query(&self, max_depth: usize) -> anyhow::Result<String> {
+        let types: HashMap<&str, &FullType> =
+            self.types.iter().map(|t| (t.name.as_str(), t)).collect();
+
+        match select(&types, &self.query_type.name, max_depth, &mut Vec::new()) {
+            Some(selection) => Ok(format!("query {}", selection)),
+            None => anyhow::bail!(
+                "no fields could be selected from the {} type",
+                self.query_type.name
+            ),
+        }
+    }
+
+    /// Keeps only the field queries whose leaf field returns the named type,
+    /// ignoring any list and non-null wrappers. Queries that can't be matched
+    /// against the schema are dropped.
+    pub(crate) fn retain_of_type(
+        &self,
+        queries: &mut Vec<FieldQuery>,
+        type_name: &str,
+    ) -> anyhow::Result<()> {
+        let types: HashMap<&str, &FullType> =
+            self.types.iter().map(|t| (t.name.as_str(), t)).collect();
+        if !types.contains_key(type_name) {
+            anyhow::bail!("the schema has no type named {}", type_name);
+        }
+
+        queries.retain(|query| {
+            leaf_type(&types, &self.query_type.name, &query.query) == Some(type_name)
+        });
+        Ok(())
+    }
+
+    /// Checks a field query against the schema, returning the reasons it is
+    /// invalid, if any. Only the problems decomposition could introduce are
+    /// looked for: unknown types, fields, and arguments, missing required
+    /// arguments, undefined variables, and selections that don't match
+    /// whether a field is a leaf.
+    pub(crate) fn validate(&self, query: &FieldQuery) -> Vec<String> {
+        let doc = match graphql_parser::parse_query::<&str>(&query.query) {
+            Ok(doc) => doc,
+            Err(e) => return vec![e.to_string()],
+        };
+        let mut validator = Validator {
+            types: self.types.iter().map(|t| (t.name.as_str(), t)).collect(),
+            variables: Vec::new(),
+            problems: Vec::new(),
+        };
+
+        for def in doc.definitions.iter() {
+            match def {
+                Definition::Operation(OperationDefinition::Query(operation)) => {
+                    validator.variables = operation
+                        .variable_definitions
+                        .iter()
+                        .map(|var| var.name)
+                        .collect();
+                    validator.selection_set(&self.query_type.name, &operation.selection_set);
+                }
+                Definition::Operation(OperationDefinition::SelectionSet(ss)) => {
+                    validator.selection_set(&self.query_type.name, ss)
+                }
+                Definition::Operation(_) => validator
+                    .problems
+                    .push(String::from("only query operations can be validated")),
+                // Generated queries never contain fragment definitions.
+                Definition::Fragment(_) => {}
+            }
+        }
+
+        validator.problems
+    }
+}
+
+struct Validator<'a, 'q> {
+    types: HashMap<&'a str, &'a FullType>,
+    // Variables defined by the operation.
+    variables: Vec<&'q str>,
+    problems: Vec<String>,
+}
+
+impl<'a, 'q> Validator<'a, 'q> {
+    fn selection_set(&mut self, parent: &str, ss: &SelectionSet<'q, &'q str>) {
+        for item in ss.items.iter() {
+            match item {
+                Selection::Field(field) => self.field(parent, field),
+                Selection::InlineFragment(fragment) => match &fragment.type_condition {
+                    Some(TypeCondition::On(name)) if !self.types.contains_key(name) => self
+                        .problems
+                        .push(format!("unknown type {} in inline fragment", name)),
+                    Some(TypeCondition::On(name)) => {
+                        self.selection_set(name, &fragment.selection_set)
+                    }
+                    None => self.selection_set(parent, &fragment.selection_set),
+                },
+                Selection::FragmentSpread(spread) => self.problems.push(format!(
+                    "unexpected fragment spread ...{}",
+                    spread.fragment_name
+                )),
+            }
+        }
+    }
+
+    fn field(&mut self, parent: &str, field: &Field<'q, &'q str>) {
+        if field.name == "__typename" {
+            return;
+        }
+        let definition = match self
+            .types
+            .get(parent)
+            .and_then(|t| t.fields.as_ref())
+            .and_then(|fields| fields.iter().find(|f| f.name == field.name))
+        {
+            Some(definition) => definition,
+            None => {
+                self.problems
+                    .push(format!("{} has no field {}", parent, field.name));
+                return;
+            }
+        };
+
+        for (name, value) in field.arguments.iter() {
+            match definition.args.iter().find(|arg| arg.name == *name) {
+                Some(arg) => self.argument(parent, field.name, arg, value),
+                None => self.problems.push(format!(
+                    "{}.{} has no argument {}",
+                    parent, field.name, name
+                )),
+            }
+        }
+        for arg in definition.args.iter() {
+            if arg.input_type.kind == "NON_NULL"
+                && arg.default_value.is_none()
+                && !field.arguments.iter().any(|(name, _)| *name == arg.name)
+            {
+                self.problems.push(format!(
+                    "{}.{} is missing its required argument {}: {}",
+                    parent, field.name, arg.name, arg.input_type
+                ));
+            }
+        }
+
+        let named = definition.field_type.named().unwrap_or_default();
+        let kind = self.types.get(named).map(|t| t.kind.as_str());
+        let is_leaf = matches!(kind, Some("SCALAR" | "ENUM"));
+        if is_leaf && !field.selection_set.items.is_empty() {
+            self.problems.push(format!(
+                "{}.{} is a leaf of type {}, so can't have a selection",
+                parent, field.name, definition.field_type
+            ));
+        } else if !is_leaf && field.selection_set.items.is_empty() {
+            self.problems.push(format!(
+                "{}.{} of type {} needs a selection",
+                parent, field.name, definition.field_type
+            ));
+        } else if !is_leaf {
+            self.selection_set(named, &field.selection_set);
+        }
+    }
+
+    /// Checks that a literal argument value is of the right kind for its
+    /// type, and that a variable is defined. Nested values aren't checked,
+    /// and nor are values of custom scalars, which may be serialized as
+    /// anything.
+    fn argument(
+        &mut self,
+        parent: &str,
+        field: &str,
+        arg: &InputValue,
+        value: &Value<'q, &'q str>,
+    ) {
+        if let Value::Variable(name) = value {
+            if !self.variables.contains(name) {
+                self.problems.push(format!(
+                    "{}.{} argument {} uses undefined variable ${}",
+                    parent, field, arg.name, name
+                ));
+            }
+            return;
+        }
+
+        let mut input_type = &arg.input_type;
+        if input_type.kind == "NON_NULL" {
+            if let Value::Null = value {
+                self.problems.push(format!(
+                    "{}.{} argument {} of type {} can't be null",
+                    parent, field, arg.name, arg.input_type
+                ));
+                return;
+            }
+            input_type = input_type.of_type.as_deref().unwrap_or(input_type);
+        }
+
+        let kind = match (input_type.kind.as_str(), input_type.name.as_deref()) {
+            ("SCALAR", Some(name @ ("Int" | "Float" | "String" | "Boolean" | "ID"))) => name,
+            ("SCALAR", _) => return,
+            (kind, _) => kind,
+        };
+        let ok = match value {
+            Value::Variable(_) | Value::Null => true,
+            Value::Int(_) => matches!(kind, "Int" | "Float" | "ID" | "LIST"),
+            Value::Float(_) => matches!(kind, "Float" | "LIST"),
+            Value::String(_) => matches!(kind, "String" | "ID" | "LIST"),
+            Value::Boolean(_) => matches!(kind, "Boolean" | "LIST"),
+            Value::Enum(_) => matches!(kind, "ENUM" | "LIST"),
+            Value::List(_) => kind == "LIST",
+            Value::Object(_) => matches!(kind, "INPUT_OBJECT" | "LIST"),
+        };
+        if !ok {
+            self.problems.push(format!(
+                "{}.{} argument {} of type {} can't be {}",
+                parent, field, arg.name, arg.input_type, value
+            ));
+        }
+    }
+}
+
+/// Returns the named type of the leaf field of a generated query, which
+/// selects a single path from the root type.
+fn leaf_type<'a>(
+    types: &HashMap<&'a str, &'a FullType>,
+    root: &'a str,
+    query: &str,
+) -> Option<&'a str> {
+    let doc = graphql_parser::parse_query::<&str>(query).ok()?;
+    let ss = doc.definitions.iter().find_map(|def| match def {
+        Definition::Operation(OperationDefinition::Query(query)) => Some(&query.selection_set),
+        Definition::Operation(OperationDefinition::SelectionSet(ss)) => Some(ss),
+        _ => None,
+    })?;
+
+    walk_leaf_type(types, root, ss)
+}
+
+fn walk_leaf_type<'a, 'q>(
+    types: &HashMap<&'a str, &'a FullType>,
+    parent: &'a str,
+    ss: &SelectionSet<'q, &'q str>,
+) -> Option<&'a str> {
+    // Skip any __typename added alongside the path with --add-typename.
+    let item = ss
+        .items
+        .iter()
+        .find(|item| !matches!(item, Selection::Field(field) if field.name == "__typename"))?;
+
+    match item {
+        Selection::Field(field) => {
+            let definition = types
+                .get(parent)?
+                .fields
+                .as_ref()?
+                .iter()
+                .find(|definition| definition.name == field.name)?;
+            let named = types.get(definition.field_type.named()?)?.name.as_str();
+            if field.selection_set.items.is_empty() {
+                Some(named)
+            } else {
+                walk_leaf_type(types, named, &field.selection_set)
+            }
+        }
+        Selection::InlineFragment(fragment) => {
+            let parent = match &fragment.type_condition {
+                Some(TypeCondition::On(name)) => types.get(name)?.name.as_str(),
+                None => parent,
+            };
+            walk_leaf_type(types, parent, &fragment.selection_set)
+        }
+        // Generated queries never contain fragment spreads.
+        Selection::FragmentSpread(_) => None,
+    }
+}
+
+fn select<'a>(
+    types: &HashMap<&'a str, &'a FullType>,
+    name: &'a str,
+    depth: usize,
+    ancestors: &mut Vec<&'a str>,
+) -> Option<String> {
+    let fields = types.get(name)?.fields.as_ref()?;
+    ancestors.push(name);
+
+    let mut selections = Vec::new();
+    for field in fields.iter() {
+        if field.name.starts_with("__")
+            || field
+                .args
+                .iter()
+                .any(|arg| arg.input_type.kind == "NON_NULL" && arg.default_value.is_none())
+        {
+            continue;
+        }
+
+        let field_type = match field.field_type.named().and_then(|t| types.get(t)) {
+            Some(field_type) => field_type,
+            None => continue,
+        };
+        match field_type.kind.as_str() {
+            "SCALAR" | "ENUM" => selections.push(field.name.clone()),
+            "OBJECT" | "INTERFACE"
+                if depth > 1 && !ancestors.contains(&field_type.name.as_str()) =>
+            {
+                if let Some(selection) = select(types, &field_type.name, depth - 1, ancestors) {
+                    selections.push(format!("{} {}", field.name, selection));
+                }
+            }
+            // Unions would need a fragment per member type; skip them.
+            _ => {}
+        }
+    }
+
+    ancestors.pop();
+    if selections.is_empty() {
+        None
+    } else {
+        Some(format!("{{ {} }}", selections.join(" ")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn field_query(query: &str) -> FieldQuery {
+        FieldQuery {
+            id: String::from("id"),
+            query: query.to_string(),
+            path: vec![String::from("a")],
+            tags: Default::default(),
+            endpoint: None,
+            lines: Vec::new(),
+            read_only: true,
+            arg_template: None,
+        }
+    }
+
+    fn named(kind: &str, name: &str) -> serde_json::Value {
+        json!({ "kind": kind, "name": name, "ofType": null })
+    }
+
+    fn non_null(of_type: serde_json::Value) -> serde_json::Value {
+        json!({ "kind": "NON_NULL", "name": null, "ofType": of_type })
+    }
+
+    fn schema() -> Schema {
+        serde_json::from_value(json!({
+            "queryType": { "name": "Query" },
+            "types": [
+                {
+                    "kind": "OBJECT",
+                    "name": "Query",
+                    "fields": [
+                        {
+                            "name": "user",
+                            "args": [{
+                                "name": "id",
+                                "defaultValue": null,
+                                "type": non_null(named("SCALAR", "ID")),
+                            }],
+                            "type": named("OBJECT", "User"),
+                        },
+                        {
+                            "name": "events",
+                            "args": [
+                                {
+                                    "name": "since",
+                                    "defaultValue": null,
+                                    "type": named("SCALAR", "DateTime"),
+                                },
+                                {
+                                    "name": "filter",
+                                    "defaultValue": null,
+                                    "type": named("SCALAR", "JSON"),
+                                },
+                                {
+                                    "name": "limit",
+                                    "defaultValue": null,
+                                    "type": named("SCALAR", "Int"),
+                                },
+                            ],
+                            "type": named("SCALAR", "String"),
+                        },
+                    ],
+                },
+                {
+                    "kind": "OBJECT",
+                    "name": "User",
+                    "fields": [{
+                        "name": "name",
+                        "args": [],
+                        "type": named("SCALAR", "String"),
+                    }],
+                },
+                { "kind": "SCALAR", "name": "ID", "fields": null },
+                { "kind": "SCALAR", "name": "Int", "fields": null },
+                { "kind": "SCALAR", "name": "String", "fields": null },
+                { "kind": "SCALAR", "name": "DateTime", "fields": null },
+                { "kind": "SCALAR", "name": "JSON", "fields": null },
+            ],
+        }))
+        .unwrap()
+    }
+
+    fn validate(query: &str) -> Vec<String> {
+        schema().validate(&field_query(query))
+    }
+
+    #[test]
+    fn valid_query_has_no_problems() {
+        assert!(validate(r#"{ user(id: "1") { name } }"#).is_empty());
+        assert!(validate("query ($id: ID!) { user(id: $id) { name } }").is_empty());
+    }
+
+    #[test]
+    fn unknown_field() {
+        assert_eq!(
+            validate(r#"{ user(id: "1") { email } }"#),
+            vec!["User has no field email"]
+        );
+    }
+
+    #[test]
+    fn missing_required_argument() {
+        assert_eq!(
+            validate("{ user { name } }"),
+            vec!["Query.user is missing its required argument id: ID!"]
+        );
+    }
+
+    #[test]
+    fn leaf_and_selection_mismatch() {
+        assert_eq!(
+            validate(r#"{ user(id: "1") }"#),
+            vec!["Query.user of type User needs a selection"]
+        );
+        assert_eq!(
+            validate(r#"{ user(id: "1") { name { first } } }"#),
+            vec!["User.name is a leaf of type String, so can't have a selection"]
+        );
+    }
+
+    #[test]
+    fn undefined_variable() {
+        assert_eq!(
+            validate("{ user(id: $id) { name } }"),
+            vec!["Query.user argument id uses undefined variable $id"]
+        );
+        assert_eq!(
+            validate("{ events(since: $since) }"),
+            vec!["Query.events argument since uses undefined variable $since"]
+        );
+    }
+
+    #[test]
+    fn custom_scalars_accept_any_literal() {
+        assert!(validate(r#"{ events(since: "2024-01-01") }"#).is_empty());
+        assert!(validate("{ events(since: 1704067200) }").is_empty());
+        assert!(validate(r#"{ events(filter: { kind: "login", after: 3 }) }"#).is_empty());
+        assert!(validate("{ events(filter: [true, 1.5]) }").is_empty());
+        assert_eq!(
+            validate(r#"{ events(limit: "10") }"#),
+            vec![r#"Query.events argument limit of type Int can't be "10""#]
+        );
+    }
+}
@@ -1,72 +1,1199 @@
-use std::{io::Read, path::PathBuf};
+use std::{
+    ffi::OsStr,
+    io::Read,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
+};
 
-use console::{style, StyledObject};
-use indicatif::ProgressIterator;
+use anyhow::Context;
+use chrome::ChromeTrace;
+use config::Config;
+use console::Term;
+use futures_util::StreamExt;
+use har::Har;
+use hyper::header::HeaderName;
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use introspection::Schema;
+use oauth::ClientCredentials;
+use otlp::Trace;
+use output::{Format, GroupBy, OutputOptions, ProgressStream, SweepLevel, Template};
+use parser::{ArgOverride, Decomposition, FieldQuery, ParseOptions};
+use rand::seq::SliceRandom;
 use structopt::StructOpt;
-use timer::{Status, Timer};
+use timer::{
+    BodyTemplate, Chain, DeadlineFormat, FailureOrder, HeaderCommand, Http2Options,
+    RepeatConcurrency, RequestTarget, Resolve, ResponsePath, Status, Timer, TimerOptions,
+    TlsVersion, Var, VariablesFormat,
+};
+use weights::Weights;
 
+mod changed;
+mod chrome;
+mod compare;
+mod config;
+mod curl;
+mod har;
+mod incremental;
+mod introspection;
+mod oauth;
+mod otlp;
+mod output;
 mod parser;
+mod pool;
+mod scan;
+mod sqlite;
 mod timer;
+mod tui;
+mod weights;
 
+/// Options are resolved in order of precedence: command line flags, then
+/// environment variables, then the configuration file and its selected profile.
+/// Only url, header, variables and timeout can be set in the configuration file.
 #[derive(Debug, StructOpt)]
 #[structopt(name = "graphql-field-timer")]
 struct Opt {
+    /// Protocols to offer through TLS ALPN, comma separated in order of
+    /// preference, eg h2,http/1.1. HTTP/2 is used if the server selects h2,
+    /// and it's an error if the server selects none of them.
+    #[structopt(long, use_delimiter = true)]
+    alpn: Vec<String>,
+
+    /// Send the Apollo-Require-Preflight header, which Apollo Server 4 requires
+    /// by default as part of its CSRF prevention. Without it, requests are
+    /// rejected with a 400 error.
+    #[structopt(long)]
+    apollo_csrf: bool,
+
+    /// Treat any GraphQL errors in a response as a failure, even if the
+    /// response also contains data.
+    #[structopt(long)]
+    assert_no_errors: bool,
+
+    /// Milliseconds to wait between batches sent with --batch-size.
+    #[structopt(long, default_value = "0")]
+    batch_pause: u64,
+
+    /// Send the queries in batches of this many at once, waiting for each
+    /// batch to complete before starting the next, to model bursts of traffic
+    /// such as a page load. This replaces --concurrency.
+    #[structopt(long)]
+    batch_size: Option<usize>,
+
+    /// Send this variable with a fresh random string on every request, so
+    /// that a server or proxy caching responses by request body can't serve a
+    /// cached response, eg _nonce. It isn't declared on the operation, since
+    /// servers reject unused variables but ignore extra ones.
+    #[structopt(long, value_name = "NAME")]
+    cache_bust_var: Option<String>,
+
+    /// Send each query twice in immediate succession and report the first
+    /// (cold) and second (warm) durations separately, to show which fields
+    /// benefit from caching. Both count as samples.
+    #[structopt(long)]
+    cache_probe: bool,
+
+    /// Set a variable for the queries that follow from a value in an earlier
+    /// response, given as "PATH => $VAR", where PATH is a dotted path into the
+    /// response data, eg "viewer.repos.0.id => $repo". Chaining forces the
+    /// queries to be sent one at a time, in order, and so can't be combined
+    /// with --batch-size or --shuffle. May be given more than once.
+    #[structopt(long, conflicts_with_all = &["batch-size", "shuffle"])]
+    chain: Vec<Chain>,
+
+    /// Include the data returned by successful responses in the JSON output.
+    #[structopt(long)]
+    capture_data: bool,
+
+    /// Send the --timeout to the server in this header, eg grpc-timeout, so
+    /// that it can stop working on queries the client has given up on.
+    #[structopt(long, requires = "timeout")]
+    deadline_header: Option<HeaderName>,
+
+    /// Format of the --deadline-header value: grpc (eg 1500m), ms, or s.
+    #[structopt(long, default_value = "grpc")]
+    deadline_format: DeadlineFormat,
+
+    /// Keep only the status and timings of successful responses, rather than
+    /// their full bodies, to reduce memory use on large runs. Failures are
+    /// always kept in full.
+    #[structopt(long)]
+    discard_success_bodies: bool,
+
+    /// Configuration file to load defaults from, such as url, header,
+    /// variables and timeout, optionally grouped into profiles. If omitted,
+    /// graphql-field-timer.toml in the current directory is used if present.
+    /// Command line flags and environment variables take precedence over it.
+    #[structopt(long, parse(from_os_str))]
+    config: Option<PathBuf>,
+
+    /// Compare two files of results saved with --format json, joining them
+    /// by query id, rather than sending any queries.
+    #[structopt(long, parse(from_os_str), number_of_values = 2, value_names = &["BEFORE", "AFTER"])]
+    compare: Vec<PathBuf>,
+
+    /// Number of queries to send at once.
+    #[structopt(long, default_value = "1")]
+    concurrency: usize,
+
+    /// Send every query at each of these concurrency levels in turn, eg
+    /// 1,2,4,8, and report the mean and 95th percentile durations and the
+    /// throughput of successful queries at each level rather than the
+    /// individual results.
+    #[structopt(
+        long,
+        use_delimiter = true,
+        conflicts_with_all = &["batch-size", "chain", "duration", "har"]
+    )]
+    concurrency_sweep: Vec<usize>,
+
+    /// Send only the SHA-256 hash of each query, as automatic persisted
+    /// queries (APQ) do. If the server doesn't recognise a hash, the full query
+    /// is sent once to register it, and that request is the one timed.
+    #[structopt(long)]
+    persisted_queries: bool,
+
+    /// Print a curl command for each field query, equivalent to the request
+    /// that would be sent, instead of sending anything.
+    #[structopt(long)]
+    print_curl: bool,
+
+    /// Print a JSON Schema describing the results written by --format json,
+    /// for tooling that consumes them, and exit.
+    #[structopt(long, hidden = true)]
+    print_output_schema: bool,
+
+    /// Report how many field queries the document expands to, grouped by
+    /// top-level field, without sending anything.
+    #[structopt(long)]
+    count_only: bool,
+
+    /// Check each field query against the schema, fetched by introspection,
+    /// and report any that are invalid without timing anything.
+    #[structopt(long, conflicts_with = "count-only")]
+    validate_only: bool,
+
+    /// Wrap each GraphQL request in this JSON envelope, for gateways that
+    /// don't accept the bare request. {{graphql}} is replaced by the request,
+    /// eg '{"operation": {{graphql}}}'.
+    #[structopt(long)]
+    body_template: Option<BodyTemplate>,
+
+    /// Read the data from this dotted path in each response, eg result.data,
+    /// for gateways that nest the GraphQL response. Defaults to data.
+    #[structopt(long)]
+    data_path: Option<ResponsePath>,
+
+    /// Read the errors from this dotted path in each response, eg
+    /// result.errors. Defaults to errors.
+    #[structopt(long)]
+    errors_path: Option<ResponsePath>,
+
+    /// Keep cycling through the queries for this many seconds, instead of
+    /// sending each query --repeat times.
+    #[structopt(long)]
+    duration: Option<f64>,
+
+    /// Whether failures are listed first or last. Within each group, results
+    /// are sorted by duration.
+    #[structopt(long, default_value = "last")]
+    failures: FailureOrder,
+
+    /// Explain why each response was classified as a success or a failure,
+    /// beneath each result in the human output and as "explanation" in the
+    /// JSON output.
+    #[structopt(long)]
+    explain: bool,
+
+    /// Treat responses whose Content-Type isn't this one as failures, rather
+    /// than trying to parse them. May be given more than once to allow
+    /// several.
+    #[structopt(long)]
+    expect_content_type: Vec<String>,
+
     #[structopt(short, long, parse(from_os_str))]
     file: Option<PathBuf>,
 
+    /// Vary an integer argument across repeats, eg page=1..100. Each repeat
+    /// uses the next value in the inclusive range, wrapping around at the end.
+    #[structopt(long)]
+    field_arg_override: Vec<ArgOverride>,
+
+    /// Operation name to send with every query. The generated queries are
+    /// renamed to match.
+    #[structopt(long)]
+    operation_name: Option<String>,
+
+    /// Only scan each JSON response for its top-level data and errors,
+    /// rather than parsing it, so that parsing very large responses doesn't
+    /// inflate the received times. The data can't be captured, chained, or
+    /// read from a nested path.
+    #[structopt(
+        long,
+        conflicts_with_all = &["capture-data", "chain", "data-path", "errors-path"]
+    )]
+    no_parse: bool,
+
+    /// Don't show a progress bar; the same as --progress-stream none.
+    #[structopt(long)]
+    no_progress: bool,
+
+    /// Where to draw the progress bar: stderr (the default), stdout, or none.
+    /// It is hidden when that stream isn't a terminal, or when the CI
+    /// environment variable is set to anything but an empty string, false or
+    /// 0. The results are always written to stdout,
+    /// after the progress bar has been cleared.
+    #[structopt(long, conflicts_with = "no-progress")]
+    progress_stream: Option<ProgressStream>,
+
+    /// Use colours and show the progress bar even when the CI environment
+    /// variable is set or output isn't to a terminal. The progress bar can
+    /// still only be drawn on a terminal.
+    #[structopt(long, conflicts_with = "no-progress")]
+    interactive: bool,
+
+    /// Format for each result in the human output, with placeholders for
+    /// {status}, {duration} (until the response headers arrived, in seconds),
+    /// {received} (until the full body arrived), {status_code}, {bytes} (size
+    /// of the response body), {connection} (new or reused), {error_code} (the
+    /// first error's extensions.code), {path}, {id}, and {query}. Use {{ and
+    /// }} for literal braces.
+    #[structopt(long)]
+    output_template: Option<Template>,
+
+    /// Export a span for each query sent to this OTLP/HTTP traces endpoint,
+    /// eg http://localhost:4318/v1/traces. This implies --timestamps.
+    #[structopt(long)]
+    otlp_endpoint: Option<String>,
+
+    /// Send an untimed { __typename } query before timing begins, so that the
+    /// first field queries don't pay for DNS, TCP, and TLS setup.
+    #[structopt(long)]
+    prime_connection: bool,
+
+    /// Output format: human, json, or chrome-trace to write each query as an
+    /// event for chrome://tracing or Perfetto. Outputs that have no trace
+    /// form, such as --count-only, are written as JSON with chrome-trace.
+    #[structopt(long, default_value = "human")]
+    format: Format,
+
+    /// Roll the results up instead of reporting each field query: top-level
+    /// reports the total and mean duration under each top-level field.
+    #[structopt(long)]
+    group_by: Option<GroupBy>,
+
+    /// Report the timings recorded in this HAR file for each field query,
+    /// instead of sending the queries. Requests are matched by query text.
+    #[structopt(long, parse(from_os_str))]
+    har: Option<PathBuf>,
+
     #[structopt(long)]
     header: Vec<String>,
 
-    #[structopt(short, long)]
-    url: String,
+    /// Set a header to the output of a shell command, given as
+    /// "NAME: COMMAND", such as a token printed by a cloud CLI. The command
+    /// is run once at startup. May be given more than once.
+    #[structopt(long)]
+    header_from_command: Vec<HeaderCommand>,
+
+    /// Size HTTP/2 flow-control windows adaptively from the measured
+    /// bandwidth-delay product, rather than using fixed windows.
+    #[structopt(
+        long,
+        conflicts_with_all = &["http2-connection-window", "http2-stream-window"]
+    )]
+    http2_adaptive_window: bool,
+
+    /// Initial HTTP/2 connection-level flow-control window in bytes. If
+    /// omitted, 5 MiB is used.
+    #[structopt(long)]
+    http2_connection_window: Option<u32>,
+
+    /// Maximum HTTP/2 frame size in bytes to accept from the server. If
+    /// omitted, 16 KiB is used.
+    #[structopt(long)]
+    http2_max_frame_size: Option<u32>,
+
+    /// Initial HTTP/2 per-stream flow-control window in bytes. If omitted,
+    /// 2 MiB is used.
+    #[structopt(long)]
+    http2_stream_window: Option<u32>,
+
+    /// Send a key unique to each query and repeat in this header, so that
+    /// servers supporting idempotency keys can deduplicate them.
+    #[structopt(long)]
+    idempotency_header: Option<HeaderName>,
+
+    /// Name of the directive used to send the queries for the fields beneath
+    /// it to another URL, eg reviews @endpoint(url: "http://reviews/graphql").
+    /// It is stripped from the queries sent to the server.
+    #[structopt(long, default_value = "endpoint")]
+    endpoint_directive: String,
+
+    /// Name of the directive used to exclude fields from timing, eg
+    /// password @timerIgnore. Everything beneath an excluded field is excluded
+    /// too.
+    #[structopt(long, default_value = "timerIgnore")]
+    ignore_directive: String,
 
+    /// Select __typename at every object level of each generated query, as
+    /// Apollo Client does. It isn't timed as a field of its own.
+    #[structopt(long)]
+    add_typename: bool,
+
+    /// Canonicalise the generated queries, sorting arguments and dropping
+    /// directives that can't change the result, so that their text and ids
+    /// don't depend on how the document is formatted.
+    #[structopt(long)]
+    normalize: bool,
+
+    /// In the human output, list just the path to each field and its
+    /// duration, rather than the full query.
+    #[structopt(long)]
+    fields_only: bool,
+
+    /// In the human output, leave out the response of each failed field query.
+    /// Distinct errors are still summarised at the end.
     #[structopt(short, long)]
+    quiet: bool,
+
+    /// Explore the results in an interactive table instead of listing them,
+    /// where they can be sorted, filtered by status, expanded to show the
+    /// query and response, and re-run one at a time.
+    #[structopt(long)]
+    tui: bool,
+
+    /// Indent the JSON output for reading, rather than writing it compactly.
+    #[structopt(long)]
+    json_pretty: bool,
+
+    /// Send the field queries in proportion to the weights in this JSON file,
+    /// which maps dotted field paths to relative weights, eg {"user": 10,
+    /// "user.avatar": 1}. A path covers the fields beneath it, the longest
+    /// matching path wins, and unlisted fields have a weight of 1. The
+    /// --repeat total is divided by weight, or with --duration queries are
+    /// sampled by weight.
+    #[structopt(long, parse(from_os_str), conflicts_with_all = &["interleave", "shuffle"])]
+    weights: Option<PathBuf>,
+
+    /// Cycle through all queries once per repeat, rather than sending each
+    /// query's repeats back to back.
+    #[structopt(long)]
+    interleave: bool,
+
+    /// Stop sending new queries once the run has taken this many seconds,
+    /// letting those in flight finish, and report the results so far. Unlike
+    /// --timeout, this bounds the whole run rather than each query.
+    #[structopt(long, conflicts_with_all = &["concurrency-sweep", "har"])]
+    max_total: Option<f64>,
+
+    /// Skip up to this many lines of the --replay file that can't be parsed,
+    /// reporting them, rather than refusing to run.
+    #[structopt(long, requires = "replay", value_name = "N")]
+    max_parse_errors: Option<usize>,
+
+    /// Refuse to run if the document decomposes into more than this many
+    /// field queries, guarding against pathological documents.
+    #[structopt(long)]
+    max_queries: Option<usize>,
+
+    /// Delay each of the first concurrent requests by a random amount up to
+    /// this many milliseconds, so they don't all launch at once. The delay is
+    /// not included in the measured durations.
+    #[structopt(long)]
+    jitter: Option<u64>,
+
+    /// Build the query from the schema of the endpoint, selecting every field
+    /// that doesn't require arguments, instead of reading a query.
+    #[structopt(long)]
+    introspect: bool,
+
+    /// Maximum depth of the query built by --introspect.
+    #[structopt(long, default_value = "3")]
+    introspect_depth: usize,
+
+    /// Maximum number of connections to open to each host. Over HTTP/1.1, each
+    /// connection carries one request at a time, so queries beyond this wait
+    /// for one to be free. Over HTTP/2, every query shares a single connection
+    /// as a stream of its own. The wait for a connection isn't timed, and
+    /// doesn't count towards --timeout.
+    #[structopt(long)]
+    max_connections_per_host: Option<usize>,
+
+    /// Client ID for --oauth-token-url.
+    #[structopt(long, env = "GRAPHQL_FIELD_TIMER_OAUTH_CLIENT_ID")]
+    oauth_client_id: Option<String>,
+
+    /// Client secret for --oauth-token-url.
+    #[structopt(
+        long,
+        env = "GRAPHQL_FIELD_TIMER_OAUTH_CLIENT_SECRET",
+        hide_env_values = true
+    )]
+    oauth_client_secret: Option<String>,
+
+    /// Obtain an access token from this OAuth2 token endpoint with the client
+    /// credentials grant, and send it as a bearer token with every query. The
+    /// token is renewed if it expires during the run.
+    #[structopt(long)]
+    oauth_token_url: Option<String>,
+
+    /// When --max-queries is exceeded, warn and time only the first field
+    /// queries up to the limit rather than failing.
+    #[structopt(long, requires = "max-queries")]
+    truncate: bool,
+
+    /// Only time fields whose query text differs from the --file at this git
+    /// ref, such as the base branch of a pull request. Every field is timed
+    /// if the diff can't be computed.
+    #[structopt(long, requires = "file", value_name = "BASE_REF")]
+    only_changed: Option<String>,
+
+    /// Only time leaf fields that return this type, such as DateTime, to find
+    /// a slow resolver wherever the type is used. The field types are looked
+    /// up by introspecting the schema; every field is timed if that fails.
+    #[structopt(long, value_name = "TYPENAME")]
+    type_filter: Option<String>,
+
+    /// Only time fields that were slower than --slower-than in this earlier
+    /// run, saved with --format json. Fields are matched by query id.
+    #[structopt(long, parse(from_os_str), requires = "slower-than")]
+    since: Option<PathBuf>,
+
+    /// Duration in seconds that a field must have exceeded in the --since run
+    /// to be timed again.
+    #[structopt(long, requires = "since", value_name = "SECONDS")]
+    slower_than: Option<f64>,
+
+    /// Only time fields tagged with this group by the tag directive.
+    #[structopt(long)]
+    only_group: Option<String>,
+
+    /// Profile in the configuration file to use. If omitted, the file's
+    /// default_profile is used if set.
+    #[structopt(long, env = "GRAPHQL_FIELD_TIMER_PROFILE")]
+    profile: Option<String>,
+
+    /// Append the results to the results table in this SQLite database,
+    /// creating it if needed, to track latencies across runs. Requires the
+    /// sqlite3 command line tool.
+    #[structopt(long, parse(from_os_str))]
+    sqlite: Option<PathBuf>,
+
+    /// Time the field queries in this file exactly as given, one per line,
+    /// rather than decomposing a document. Lines may be JSON strings, such as
+    /// the query values from the JSON output.
+    #[structopt(long, parse(from_os_str), conflicts_with_all = &["file", "introspect"])]
+    replay: Option<PathBuf>,
+
+    /// Print the TLS version and cipher suite negotiated with each server, to
+    /// confirm nothing weak is in use.
+    #[structopt(long)]
+    show_tls: bool,
+
+    /// Number of times to send each query. The median duration is reported.
+    #[structopt(long, default_value = "1")]
+    repeat: usize,
+
+    /// How the repeats of each query are scheduled: parallel, where they are
+    /// sent like any other query and may overlap, or serial, where each
+    /// waits for the previous one. Different queries still run concurrently.
+    /// Defaults to parallel.
+    #[structopt(long, conflicts_with_all = &["batch-size", "duration", "interleave"])]
+    repeat_concurrency: Option<RepeatConcurrency>,
+
+    /// Size in bytes of each socket's receive buffer. If omitted, the OS
+    /// default is used.
+    #[structopt(long)]
+    recv_buffer_size: Option<u32>,
+
+    /// Form of the HTTP request target: origin (POST /graphql) or absolute
+    /// (POST https://host/graphql).
+    #[structopt(long, default_value = "origin")]
+    request_target: RequestTarget,
+
+    /// Connect to ADDR whenever connecting to HOST on PORT, given as
+    /// HOST:PORT:ADDR. The Host header and TLS server name still use HOST. May
+    /// be given more than once.
+    #[structopt(long)]
+    resolve: Vec<Resolve>,
+
+    /// Retry each query up to this many times if it fails transiently: with
+    /// a transport error, such as a timeout, or an HTTP 429, 502, 503, or 504.
+    /// Only the final attempt is timed. Mutations and subscriptions are only
+    /// retried with --retry-mutations.
+    #[structopt(long, default_value = "0")]
+    retries: usize,
+
+    /// Total number of retries allowed across the whole run. Once it's spent,
+    /// failures are recorded without being retried.
+    #[structopt(long)]
+    retry_budget: Option<usize>,
+
+    /// Retry mutations and subscriptions loaded with --replay as well as
+    /// queries. A failure such as a timeout or a 502 doesn't mean a mutation
+    /// wasn't applied, so retrying it may apply it twice; consider
+    /// --idempotency-header.
+    #[structopt(long)]
+    retry_mutations: bool,
+
+    /// Size in bytes of each socket's send buffer. If omitted, the OS default
+    /// is used.
+    #[structopt(long)]
+    send_buffer_size: Option<u32>,
+
+    /// Send the queries in a random order.
+    #[structopt(long)]
+    shuffle: bool,
+
+    /// Stream the request body in chunks and report the upload time
+    /// separately in the JSON output.
+    #[structopt(long)]
+    stream_body: bool,
+
+    /// Whether to set TCP_NODELAY on each connection, so that small requests
+    /// aren't delayed by Nagle's algorithm: true or false.
+    #[structopt(long, default_value = "true", parse(try_from_str))]
+    tcp_nodelay: bool,
+
+    /// Milliseconds each of the --users waits after a response before sending
+    /// its next query.
+    #[structopt(long, requires = "users", value_name = "MS")]
+    think_time: Option<u64>,
+
+    /// Include the wall clock time at which each query was started in the
+    /// JSON output, for correlation with server logs.
+    #[structopt(long)]
+    timestamps: bool,
+
+    /// Name of the directive used to tag fields, eg @timer(group: "critical").
+    /// It is stripped from the queries sent to the server.
+    #[structopt(long, default_value = "timer")]
+    tag_directive: String,
+
+    /// Give up on any query that takes longer than this many seconds,
+    /// including reading its response. A query that times out fails.
+    #[structopt(long)]
+    timeout: Option<f64>,
+
+    /// Minimum TLS version to negotiate: 1.2 or 1.3. The connection fails if
+    /// the server can't meet it.
+    #[structopt(long, default_value = "1.2")]
+    tls_min_version: TlsVersion,
+
+    #[structopt(short, long, env = "GRAPHQL_FIELD_TIMER_URL")]
+    url: Option<String>,
+
+    /// Simulate this many virtual users for the --duration, each sending one
+    /// query at a time, with any --think-time between them. This replaces
+    /// --concurrency, and respects any --weights.
+    #[structopt(
+        long,
+        requires = "duration",
+        conflicts_with_all = &["batch-size", "chain", "concurrency-sweep", "repeat-concurrency"]
+    )]
+    users: Option<usize>,
+
+    /// Set a single variable, given as NAME=VALUE, overriding --variables.
+    /// Numbers, booleans, and null are sent as such; anything else is sent as
+    /// a string. May be given more than once.
+    #[structopt(long)]
+    var: Vec<Var>,
+
+    #[structopt(short, long, env = "GRAPHQL_FIELD_TIMER_VARIABLES")]
     variables: Option<String>,
+
+    /// Syntax of the variables: json, or json5 to allow comments and trailing
+    /// commas.
+    #[structopt(long, default_value = "json")]
+    variables_format: VariablesFormat,
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let opt = Opt::from_args();
-
-    // Parse the GraphQL queries into the individual field queries we're going
-    // to send.
-    let raw = String::from_utf8(opt.file.map(std::fs::read).unwrap_or_else(|| {
-        let mut buf = Vec::new();
-        std::io::stdin().read_to_end(&mut buf)?;
-        Ok(buf)
-    })?)?;
-    let doc = graphql_parser::parse_query::<&str>(&raw)?;
-    let queries = parser::parse_document(&doc)?;
+    let mut opt = Opt::from_args();
+    let interactive = configure_console(&opt);
+    if let [before, after] = opt.compare.as_slice() {
+        return compare::compare(&output_options(&opt), before, after);
+    }
+    if opt.print_output_schema {
+        return output::write_output_schema();
+    }
+    let config = Config::load(opt.config.as_deref())?.select_profile(opt.profile.as_deref())?;
+
+    // Get the field queries to send: either those given to replay, or those
+    // decomposed from a GraphQL document.
+    let mut timer = None;
+    let mut embedded_variables = None;
+    let Decomposition {
+        mut queries,
+        skipped,
+    } = match &opt.replay {
+        Some(path) => {
+            let raw = std::fs::read_to_string(path)
+                .with_context(|| format!("reading {}", path.display()))?;
+            let max_parse_errors = opt.max_parse_errors.unwrap_or_default();
+            on_large_stack(|| parser::replay_queries(&raw, max_parse_errors))?
+        }
+        None => {
+            let (document, variables) =
+                parser::split_frontmatter(&read_document(&opt, &config, &mut timer).await?)?;
+            embedded_variables = variables;
+            decompose(
+                &document,
+                opt.file.as_deref(),
+                &ParseOptions {
+                    add_typename: opt.add_typename,
+                    endpoint_directive: opt.endpoint_directive.clone(),
+                    ignore_directive: opt.ignore_directive.clone(),
+                    normalize: opt.normalize,
+                    operation_name: opt.operation_name.clone(),
+                    tag_directive: opt.tag_directive.clone(),
+                },
+            )?
+        }
+    };
+    if !opt.field_arg_override.is_empty() {
+        on_large_stack(|| parser::apply_arg_overrides(&mut queries, &opt.field_arg_override))?;
+    }
+    // Variables embedded in the document take precedence over the config
+    // file, but not over --variables.
+    if opt.variables.is_none() {
+        opt.variables = embedded_variables;
+    }
+    if let Some(group) = &opt.only_group {
+        queries.retain(|query| query.tags.get("group") == Some(group));
+    }
+
+    if let (Some(base), Some(file)) = (&opt.only_changed, &opt.file) {
+        match changed::changed_lines(file, base) {
+            Ok(lines) => changed::retain_changed(&mut queries, &lines),
+            Err(e) => eprintln!(
+                "{}",
+                console::style(format!(
+                    "Cannot diff the document against {}, so timing every field: {:#}",
+                    base, e
+                ))
+                .yellow()
+            ),
+        }
+    }
+
+    if let (Some(path), Some(threshold)) = (&opt.since, opt.slower_than) {
+        let slow = compare::slow_ids(path, threshold)?;
+        queries.retain(|query| slow.contains(&query.id));
+        let missing = slow
+            .iter()
+            .filter(|id| !queries.iter().any(|query| &query.id == *id))
+            .count();
+        if missing > 0 {
+            eprintln!(
+                "{}",
+                console::style(format!(
+                    "{} fields slower than {}s in {} are no longer in the document, or their \
+                     queries have changed",
+                    missing,
+                    threshold,
+                    path.display()
+                ))
+                .yellow()
+            );
+        }
+    }
+
+    if let Some(type_name) = &opt.type_filter {
+        let introspector = match timer.as_ref() {
+            Some(timer) => timer,
+            None => timer.insert(create_timer(&opt, &config)?),
+        };
+        match Schema::fetch(introspector).await {
+            Ok(schema) => schema.retain_of_type(&mut queries, type_name)?,
+            Err(e) => eprintln!(
+                "{}",
+                console::style(format!(
+                    "Cannot introspect the schema to find fields of type {}, so timing every \
+                     field: {:#}",
+                    type_name, e
+                ))
+                .yellow()
+            ),
+        }
+    }
+
+    if opt.validate_only {
+        let introspector = match timer.as_ref() {
+            Some(timer) => timer,
+            None => timer.insert(create_timer(&opt, &config)?),
+        };
+        let schema = Schema::fetch(introspector)
+            .await
+            .context("cannot introspect the schema to validate against")?;
+        let problems: Vec<_> = queries.iter().map(|query| schema.validate(query)).collect();
+        output::write_validation(opt.format, &queries, &problems)?;
+        output::write_skipped(&skipped);
+        let invalid = problems
+            .iter()
+            .filter(|problems| !problems.is_empty())
+            .count();
+        if invalid > 0 {
+            anyhow::bail!("{} field queries are invalid", invalid);
+        }
+        return Ok(());
+    }
+
+    if opt.count_only {
+        output::write_count(opt.format, &queries)?;
+        output::write_skipped(&skipped);
+        return Ok(());
+    }
+
+    if let Some(max) = opt.max_queries.filter(|max| queries.len() > *max) {
+        if !opt.truncate {
+            anyhow::bail!(
+                "the document decomposes into {} field queries, more than --max-queries {}; \
+                 narrow it with --only-group or the ignore directive, or pass --truncate",
+                queries.len(),
+                max
+            );
+        }
+        eprintln!(
+            "{}",
+            console::style(format!(
+                "The document decomposes into {} field queries; only timing the first {}",
+                queries.len(),
+                max
+            ))
+            .yellow()
+        );
+        queries.truncate(max);
+    }
+
+    if opt.print_curl {
+        let timer = match timer {
+            Some(timer) => timer,
+            None => create_timer(&opt, &config)?,
+        };
+        for query in queries.iter() {
+            println!("# {}\n{}\n", query.path.join("."), timer.curl(query).await?);
+        }
+        output::write_skipped(&skipped);
+        return Ok(());
+    }
+
+    if let Some(path) = &opt.har {
+        let (results, unmatched) = Har::load(path)?.results(&queries, opt.failures)?;
+        output::write(&output_options(&opt), &results)?;
+        output::write_skipped(&skipped);
+        output::write_unmatched(&unmatched);
+        return Ok(());
+    }
+
+    let weights = opt.weights.as_deref().map(Weights::load).transpose()?;
+
+    if !opt.concurrency_sweep.is_empty() {
+        let mut levels = Vec::new();
+        for concurrency in opt.concurrency_sweep.iter().copied() {
+            // Each level gets a new timer, so that no connections carry over
+            // from the previous level.
+            let mut timer = create_timer(&opt, &config)?;
+            timer.set_concurrency(concurrency);
+            if opt.prime_connection {
+                prime_connection(&timer).await?;
+            }
+
+            let started = Instant::now();
+            let results: Vec<_> = timer
+                .stream(schedule(&opt, &queries, weights.as_ref())?)
+                .collect()
+                .await;
+            let wall = started.elapsed();
+            let results = results.into_iter().collect::<anyhow::Result<Vec<_>>>()?;
+            let succeeded = results
+                .iter()
+                .filter(|result| result.status == Status::Success)
+                .map(|result| result.samples.len())
+                .sum();
+            let durations = results.iter().map(|result| result.duration).collect();
+            levels.push(SweepLevel::new(concurrency, durations, succeeded, wall));
+        }
+        output::write_sweep(&output_options(&opt), &levels)?;
+        output::write_skipped(&skipped);
+        return Ok(());
+    }
 
     // Set up the timer.
-    let mut timer = Timer::new(&opt.url, opt.header, opt.variables)?;
+    let mut timer = match timer {
+        Some(timer) => timer,
+        None => create_timer(&opt, &config)?,
+    };
+    if opt.prime_connection {
+        prime_connection(&timer).await?;
+    }
 
     // Actually send the GraphQL queries.
-    for query in queries.into_iter().progress() {
-        timer.send_query(&query).await?;
+    let started = Instant::now();
+    if let Some(max_total) = opt.max_total {
+        timer.set_deadline(started + Duration::from_secs_f64(max_total));
     }
+    // The wall clock time is only ever displayed, such as to label the run's
+    // results; durations are all measured from the monotonic clock.
+    let run_started = SystemTime::now();
+    let mut trace = opt.otlp_endpoint.as_ref().map(|_| Trace::start());
+    let mut chrome_trace = (opt.format == Format::ChromeTrace).then(ChromeTrace::start);
+    let (schedule, progress): (Box<dyn Iterator<Item = _>>, _) = match opt.duration {
+        Some(secs) => {
+            let deadline = Instant::now() + Duration::from_secs_f64(secs);
+            let progress = ProgressBar::new_spinner().with_style(
+                ProgressStyle::default_spinner()
+                    .template("{spinner} {pos} sent [{elapsed_precise}]\n{wide_msg}"),
+            );
+            progress.enable_steady_tick(100);
+            (
+                match &weights {
+                    Some(weights) => Box::new(
+                        weights
+                            .sample(&queries)?
+                            .take_while(move |_| Instant::now() < deadline),
+                    ),
+                    None => Box::new(
+                        rounds(&queries, opt.shuffle)
+                            .take_while(move |_| Instant::now() < deadline),
+                    ),
+                },
+                progress,
+            )
+        }
+        None => {
+            let schedule = schedule(&opt, &queries, weights.as_ref())?;
+            let progress = ProgressBar::new(schedule.len() as u64).with_style(
+                ProgressStyle::default_bar().template(
+                    "{wide_bar} {pos}/{len} [{elapsed_precise} < {eta_precise}]\n{wide_msg}",
+                ),
+            );
+            (Box::new(schedule.into_iter()), progress)
+        }
+    };
+    // The progress bar would only clutter logs when not run interactively.
+    progress.set_draw_target(if opt.no_progress || !interactive {
+        ProgressDrawTarget::hidden()
+    } else {
+        opt.progress_stream.unwrap_or_default().draw_target()
+    });
+    timer.set_observer(progress.clone());
+    let results: Vec<_> = timer.stream(schedule).collect().await;
+    for result in results.into_iter() {
+        let result = result?;
+        if let Some(trace) = trace.as_mut() {
+            trace.add(&result);
+        }
+        if let Some(chrome_trace) = chrome_trace.as_mut() {
+            chrome_trace.add(&result);
+        }
+        timer.record(result);
+    }
+    progress.finish_and_clear();
+    // Queries re-run from --tui still notify the progress bar, which would
+    // otherwise be drawn again.
+    progress.set_draw_target(ProgressDrawTarget::hidden());
+    let wall = started.elapsed();
 
     // Output our results.
-    for result in timer.results().into_iter() {
-        println!(
-            "{} {} {}",
-            render_status(result.status),
-            style(format!(" {:.3}s ", result.duration.as_secs_f64())).dim(),
-            result.query,
+    let deadline_reached = timer.deadline_reached();
+    let results = timer.results(opt.failures);
+    if opt.tui {
+        tui::explore(&timer, &results).await?;
+    } else if let Some(chrome_trace) = chrome_trace {
+        chrome_trace.write(opt.json_pretty)?;
+    } else {
+        output::write(&output_options(&opt), &results)?;
+    }
+    output::write_skipped(&skipped);
+    output::write_errors(&results);
+    if deadline_reached {
+        eprintln!(
+            "{}",
+            console::style(format!(
+                "The run reached --max-total after {:.3}s; queries not yet sent were \
+                 skipped, so these results are partial",
+                wall.as_secs_f64()
+            ))
+            .yellow()
         );
-        if result.status == Status::Failure {
-            println!("{}", result.dump_response());
-        }
     }
+    output::write_summary(&results, wall, timer.retry_usage());
+    if let Some(path) = &opt.sqlite {
+        sqlite::append(path, run_started, &results)?;
+    }
+
+    if let (Some(trace), Some(endpoint)) = (trace, &opt.otlp_endpoint) {
+        trace.export(endpoint).await?;
+    }
+
+    Ok(())
+}
 
+/// Sends an untimed query to establish a connection before timing begins.
+async fn prime_connection(timer: &Timer) -> anyhow::Result<()> {
+    timer
+        .fetch("{ __typename }")
+        .await
+        .context("priming the connection")?;
     Ok(())
 }
 
-fn render_status(status: Status) -> StyledObject<String> {
-    match status {
-        Status::Success => style(" OK  ".into()).black().on_green(),
-        Status::Failure => style(" ERR ".into()).white().on_red(),
+/// Stack size for the thread that parses queries.
+const PARSE_STACK_SIZE: usize = 256 * 1024 * 1024;
+
+/// Configures colours based on whether the output is likely to be read by a
+/// person, rather than collected in a log by automation such as a container
+/// or CI job, and returns true if so. Colours are disabled when it isn't,
+/// unless forced with --interactive; the console crate already disables them
+/// on streams that aren't terminals.
+fn configure_console(opt: &Opt) -> bool {
+    if opt.interactive {
+        console::set_colors_enabled(true);
+        console::set_colors_enabled_stderr(true);
+        return true;
+    }
+
+    if is_ci(std::env::var_os("CI").as_deref()) {
+        console::set_colors_enabled(false);
+        console::set_colors_enabled_stderr(false);
+        return false;
+    }
+
+    // The progress bar may be drawn on either stream, and is only drawn if
+    // the one chosen is a terminal.
+    Term::stderr().is_term() || Term::stdout().is_term()
+}
+
+/// Returns true if the CI environment variable has the given value. CI
+/// services set it to true or 1, and it's sometimes set to false or 0 to
+/// opt out.
+fn is_ci(value: Option<&OsStr>) -> bool {
+    match value.and_then(OsStr::to_str) {
+        Some(value) => !matches!(
+            value.trim().to_ascii_lowercase().as_str(),
+            "" | "false" | "0"
+        ),
+        None => value.is_some(),
+    }
+}
+
+/// Gets the GraphQL document, either from the user or by building one from the
+/// schema. The timer used to introspect the schema is kept for reuse.
+async fn read_document(
+    opt: &Opt,
+    config: &Config,
+    timer: &mut Option<Timer>,
+) -> anyhow::Result<String> {
+    if opt.introspect {
+        let timer = timer.insert(create_timer(opt, config)?);
+        return Schema::fetch(timer).await?.query(opt.introspect_depth);
+    }
+
+    Ok(String::from_utf8(
+        opt.file.as_ref().map(std::fs::read).unwrap_or_else(|| {
+            let mut buf = Vec::new();
+            std::io::stdin().read_to_end(&mut buf)?;
+            Ok(buf)
+        })?,
+    )?)
+}
+
+/// Parses and decomposes the document. The file the document was read from,
+/// if any, is used to describe parse errors.
+fn decompose(
+    raw: &str,
+    file: Option<&Path>,
+    options: &ParseOptions,
+) -> anyhow::Result<Decomposition> {
+    on_large_stack(|| {
+        let doc = graphql_parser::parse_query::<&str>(raw)
+            .map_err(|e| parser::describe_parse_error(raw, &e, file))?;
+        parser::parse_document(&doc, options)
+    })
+}
+
+/// Runs work that parses or walks queries. This recurses once per level of
+/// nesting, including through fragment spreads, so it runs on a thread with a
+/// much larger stack than usual to cope with deeply nested queries.
+fn on_large_stack<T, F>(f: F) -> anyhow::Result<T>
+where
+    T: Send,
+    F: FnOnce() -> anyhow::Result<T> + Send,
+{
+    std::thread::scope(|scope| {
+        let handle = std::thread::Builder::new()
+            .name(String::from("parse"))
+            .stack_size(PARSE_STACK_SIZE)
+            .spawn_scoped(scope, f)?;
+
+        match handle.join() {
+            Ok(result) => result,
+            Err(e) => std::panic::resume_unwind(e),
+        }
+    })
+}
+
+fn create_timer(opt: &Opt, config: &Config) -> anyhow::Result<Timer> {
+    let url = match opt.url.as_ref().or(config.url.as_ref()) {
+        Some(url) => url,
+        None => anyhow::bail!("no URL given with --url or in the config file"),
+    };
+
+    let oauth = match (
+        &opt.oauth_token_url,
+        &opt.oauth_client_id,
+        &opt.oauth_client_secret,
+    ) {
+        (None, _, _) => None,
+        (Some(token_url), Some(client_id), Some(client_secret)) => Some(ClientCredentials {
+            token_url: token_url.clone(),
+            client_id: client_id.clone(),
+            client_secret: client_secret.clone(),
+        }),
+        _ => {
+            anyhow::bail!("--oauth-token-url requires --oauth-client-id and --oauth-client-secret")
+        }
+    };
+
+    Timer::new(
+        url,
+        config.merge_headers(opt.header.clone()),
+        opt.variables.clone().or_else(|| config.variables()),
+        TimerOptions {
+            alpn: opt.alpn.clone(),
+            apollo_csrf: opt.apollo_csrf,
+            assert_no_errors: opt.assert_no_errors,
+            batch_pause: Duration::from_millis(opt.batch_pause),
+            batch_size: opt.batch_size,
+            body_template: opt.body_template.clone(),
+            cache_bust_var: opt.cache_bust_var.clone(),
+            cache_probe: opt.cache_probe,
+            chains: opt.chain.clone(),
+            capture_data: opt.capture_data,
+            // Chained variables are only useful if each query waits for the
+            // ones before it.
+            concurrency: if opt.chain.is_empty() {
+                opt.concurrency
+            } else {
+                1
+            },
+            data_path: opt.data_path.clone(),
+            deadline_format: opt.deadline_format,
+            deadline_header: opt.deadline_header.clone(),
+            discard_success_bodies: opt.discard_success_bodies,
+            errors_path: opt.errors_path.clone(),
+            expect_content_types: opt.expect_content_type.clone(),
+            explain: opt.explain,
+            header_commands: opt.header_from_command.clone(),
+            http2: Http2Options {
+                adaptive_window: opt.http2_adaptive_window,
+                connection_window: opt.http2_connection_window,
+                max_frame_size: opt.http2_max_frame_size,
+                stream_window: opt.http2_stream_window,
+            },
+            idempotency_header: opt.idempotency_header.clone(),
+            jitter: opt.jitter.map(Duration::from_millis),
+            max_connections_per_host: opt.max_connections_per_host,
+            oauth,
+            operation_name: opt.operation_name.clone(),
+            persisted_queries: opt.persisted_queries,
+            no_parse: opt.no_parse,
+            recv_buffer_size: opt.recv_buffer_size,
+            repeat_concurrency: opt.repeat_concurrency.unwrap_or_default(),
+            request_target: opt.request_target,
+            resolve: opt.resolve.clone(),
+            retries: opt.retries,
+            retry_budget: opt.retry_budget,
+            retry_mutations: opt.retry_mutations,
+            send_buffer_size: opt.send_buffer_size,
+            show_tls: opt.show_tls,
+            stream_body: opt.stream_body,
+            tcp_nodelay: opt.tcp_nodelay,
+            think_time: Duration::from_millis(opt.think_time.unwrap_or_default()),
+            // Spans can only be exported if we know when each query started.
+            timestamps: opt.timestamps
+                || opt.otlp_endpoint.is_some()
+                || opt.format == Format::ChromeTrace,
+            timeout: opt.timeout.or(config.timeout).map(Duration::from_secs_f64),
+            tls_min_version: opt.tls_min_version,
+            users: opt.users,
+            vars: opt.var.clone(),
+            variables_format: opt.variables_format,
+        },
+    )
+}
+
+fn output_options(opt: &Opt) -> OutputOptions {
+    OutputOptions {
+        fields_only: opt.fields_only,
+        format: opt.format,
+        group_by: opt.group_by,
+        json_pretty: opt.json_pretty,
+        quiet: opt.quiet,
+        template: opt.output_template.clone(),
+    }
+}
+
+/// Builds the order in which the queries will be sent, taking repeats into
+/// account. Each query is paired with the index of the repeat it belongs to.
+/// With weights, the same total number of queries is instead divided between
+/// them by weight.
+fn schedule<'a>(
+    opt: &Opt,
+    queries: &'a [FieldQuery],
+    weights: Option<&Weights>,
+) -> anyhow::Result<Vec<(usize, &'a FieldQuery)>> {
+    if let Some(weights) = weights {
+        return weights.schedule(queries, opt.repeat * queries.len());
+    }
+
+    Ok(if opt.interleave {
+        rounds(queries, opt.shuffle)
+            .take(opt.repeat * queries.len())
+            .collect()
+    } else {
+        let mut schedule: Vec<(usize, &FieldQuery)> = queries
+            .iter()
+            .flat_map(|query| (0..opt.repeat).map(move |i| (i, query)))
+            .collect();
+        if opt.shuffle {
+            schedule.shuffle(&mut rand::thread_rng());
+        }
+        schedule
+    })
+}
+
+/// Cycles through the queries endlessly, one round per repeat. Each round is
+/// shuffled separately so that every query's repeats remain spread across the
+/// whole run.
+fn rounds(queries: &[FieldQuery], shuffle: bool) -> impl Iterator<Item = (usize, &FieldQuery)> {
+    // Without any queries, there would be no end to the empty rounds.
+    let count = if queries.is_empty() { 0 } else { usize::MAX };
+    let mut rng = rand::thread_rng();
+
+    (0..count).flat_map(move |i| {
+        let mut round: Vec<(usize, &FieldQuery)> = queries.iter().map(|query| (i, query)).collect();
+        if shuffle {
+            round.shuffle(&mut rng);
+        }
+        round
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ci_is_only_set_by_a_truthy_value() {
+        assert!(!is_ci(None));
+        for value in ["", "false", "FALSE", "0"] {
+            assert!(!is_ci(Some(OsStr::new(value))), "{:?}", value);
+        }
+        for value in ["true", "1", "yes"] {
+            assert!(is_ci(Some(OsStr::new(value))), "{:?}", value);
+        }
     }
-    .bright()
-    .bold()
 }
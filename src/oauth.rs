@@ -0,0 +1,124 @@
+//! Authentication with an access token obtained through the OAuth2 client
+//! credentials grant.
+
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use serde::Deserialize;
+use tokio::sync::Mutex;
+
+use crate::timer::{Timer, TimerOptions};
+
+/// How long before the token expires that it's replaced, so that it doesn't
+/// expire while a request is in flight.
+const EXPIRY_MARGIN: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone)]
+pub(crate) struct ClientCredentials {
+    pub(crate) token_url: String,
+    pub(crate) client_id: String,
+    pub(crate) client_secret: String,
+}
+
+/// Provides an access token, requesting a new one from the token endpoint
+/// whenever the current one is about to expire.
+pub(crate) struct TokenSource {
+    client: Box<Timer>,
+    token: Mutex<Option<Token>>,
+}
+
+struct Token {
+    access_token: String,
+    expires: Option<Instant>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: Option<u64>,
+}
+
+impl TokenSource {
+    /// Creates a source of tokens from the given endpoint, which is reached
+    /// the same way as the queries, with the given options.
+    pub(crate) fn new(
+        credentials: &ClientCredentials,
+        options: &TimerOptions,
+    ) -> anyhow::Result<Self> {
+        // The client authenticates with HTTP Basic authentication, with the
+        // credentials form encoded first as RFC 6749 requires.
+        let basic = base64::encode(format!(
+            "{}:{}",
+            form_encode(&credentials.client_id),
+            form_encode(&credentials.client_secret)
+        ));
+        let client = Timer::new(
+            &credentials.token_url,
+            vec![format!("Authorization: Basic {}", basic)],
+            None,
+            options.transport(),
+        )
+        .with_context(|| format!("invalid OAuth token URL {}", credentials.token_url))?;
+
+        Ok(Self {
+            client: Box::new(client),
+            token: Mutex::new(None),
+        })
+    }
+
+    /// Returns a current access token.
+    pub(crate) async fn access_token(&self) -> anyhow::Result<String> {
+        let mut token = self.token.lock().await;
+        if let Some(token) = token.as_ref() {
+            if token.expires.is_none_or(|expires| Instant::now() < expires) {
+                return Ok(token.access_token.clone());
+            }
+        }
+
+        let fetched = self.fetch().await?;
+        let access_token = fetched.access_token.clone();
+        *token = Some(fetched);
+        Ok(access_token)
+    }
+
+    async fn fetch(&self) -> anyhow::Result<Token> {
+        let requested = Instant::now();
+        // The request goes through the same path as the queries, which asks
+        // for a token in turn, so the future must be boxed.
+        let (status, body) = Box::pin(self.client.post_form("grant_type=client_credentials"))
+            .await
+            .context("requesting an OAuth access token")?;
+        if !status.is_success() {
+            anyhow::bail!(
+                "the OAuth token endpoint responded with status {}: {}",
+                status,
+                String::from_utf8_lossy(&body)
+            );
+        }
+
+        let response: TokenResponse = serde_json::from_slice(&body)
+            .with_context(|| format!("error parsing OAuth token response: {:?}", body))?;
+        Ok(Token {
+            access_token: response.access_token,
+            expires: response.expires_in.map(|secs| {
+                (requested + Duration::from_secs(secs))
+                    .checked_sub(EXPIRY_MARGIN)
+                    .unwrap_or(requested)
+            }),
+        })
+    }
+}
+
+/// Encodes a value as application/x-www-form-urlencoded.
+fn form_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'*' => {
+                (b as char).to_string()
+            }
+            b' ' => String::from("+"),
+            b => format!("%{:02X}", b),
+        })
+        .collect()
+}
@@ -0,0 +1,137 @@
+//! Export of the query timings as OpenTelemetry spans, sent to a collector as
+//! OTLP/HTTP with JSON encoding.
+
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use rand::Rng;
+use serde_json::{json, Value};
+
+use crate::timer::{Result, Status, Timer, TimerOptions};
+
+const SERVICE_NAME: &str = "graphql-field-timer";
+
+// Span kinds and status codes, as defined by the OTLP protobuf schema.
+const SPAN_KIND_INTERNAL: u8 = 1;
+const SPAN_KIND_CLIENT: u8 = 3;
+const STATUS_CODE_OK: u8 = 1;
+const STATUS_CODE_ERROR: u8 = 2;
+
+/// A trace of a single run, with a span for each query sent beneath a parent
+/// span covering the whole run.
+#[derive(Debug)]
+pub(crate) struct Trace {
+    trace_id: String,
+    root_id: String,
+    started: SystemTime,
+    // Measures the run's duration and places each span within it, which may
+    // not match the change in the wall clock if it was adjusted during the
+    // run. The wall clock is only read once, to anchor the trace.
+    clock: Instant,
+    spans: Vec<Value>,
+}
+
+impl Trace {
+    pub(crate) fn start() -> Self {
+        Self {
+            trace_id: random_id(16),
+            root_id: random_id(8),
+            started: SystemTime::now(),
+            clock: Instant::now(),
+            spans: Vec::new(),
+        }
+    }
+
+    /// Adds a span for a single sample of a query. Results without a start
+    /// time are ignored, since the span can't be placed.
+    pub(crate) fn add(&mut self, result: &Result) {
+        let started = match result.offset(self.clock) {
+            Some(offset) => self.started + offset,
+            None => return,
+        };
+
+        self.spans.push(json!({
+            "traceId": self.trace_id,
+            "spanId": random_id(8),
+            "parentSpanId": self.root_id,
+            "name": result.query.path.join("."),
+            "kind": SPAN_KIND_CLIENT,
+            "startTimeUnixNano": unix_nanos(started),
+            "endTimeUnixNano": unix_nanos(started + result.duration),
+            "attributes": [
+                attribute("graphql.query.id", json!({ "stringValue": result.query.id })),
+                attribute("graphql.status", json!({ "stringValue": match result.status {
+                    Status::Success => "ok",
+                    Status::Failure => "error",
+                }})),
+                attribute("http.status_code", json!({ "intValue": result.status_code.to_string() })),
+            ],
+            "status": { "code": match result.status {
+                Status::Success => STATUS_CODE_OK,
+                Status::Failure => STATUS_CODE_ERROR,
+            }},
+        }));
+    }
+
+    /// Ends the run's span and sends the trace to the collector at the given
+    /// traces endpoint, eg http://localhost:4318/v1/traces.
+    pub(crate) async fn export(mut self, endpoint: &str) -> anyhow::Result<()> {
+        self.spans.push(json!({
+            "traceId": self.trace_id,
+            "spanId": self.root_id,
+            "name": SERVICE_NAME,
+            "kind": SPAN_KIND_INTERNAL,
+            "startTimeUnixNano": unix_nanos(self.started),
+            "endTimeUnixNano": unix_nanos(self.started + self.clock.elapsed()),
+        }));
+
+        let request = json!({
+            "resourceSpans": [{
+                "resource": {
+                    "attributes": [attribute("service.name", json!({ "stringValue": SERVICE_NAME }))],
+                },
+                "scopeSpans": [{
+                    "scope": { "name": SERVICE_NAME },
+                    "spans": self.spans,
+                }],
+            }],
+        });
+
+        let exporter = Timer::new(endpoint, Vec::new(), None, TimerOptions::default())
+            .with_context(|| format!("invalid OTLP endpoint {}", endpoint))?;
+        let status = exporter
+            .post_json(&request)
+            .await
+            .with_context(|| format!("exporting spans to {}", endpoint))?;
+        if !status.is_success() {
+            anyhow::bail!(
+                "exporting spans to {} failed with status {}",
+                endpoint,
+                status
+            );
+        }
+
+        Ok(())
+    }
+}
+
+fn attribute(key: &str, value: Value) -> Value {
+    json!({ "key": key, "value": value })
+}
+
+/// Returns a random hex encoded identifier of the given number of bytes.
+fn random_id(bytes: usize) -> String {
+    let mut rng = rand::thread_rng();
+    (0..bytes)
+        .map(|_| format!("{:02x}", rng.gen::<u8>()))
+        .collect()
+}
+
+/// Timestamps are encoded as strings, since they exceed what JSON numbers can
+/// represent exactly.
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
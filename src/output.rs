@@ -0,0 +1,892 @@
+use std::{collections::BTreeMap, str::FromStr, time::Duration};
+
+use console::{style, StyledObject};
+use indicatif::{ProgressBar, ProgressDrawTarget};
+use itertools::Itertools;
+use serde::Serialize;
+use serde_json::json;
+
+use crate::{
+    parser::{FieldQuery, SkippedQuery},
+    timer::{RequestObserver, Result, RetryUsage, Status},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Format {
+    Human,
+    Json,
+    /// Trace events for chrome://tracing. Output with no trace form is
+    /// written as JSON.
+    ChromeTrace,
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "human" => Ok(Self::Human),
+            "json" => Ok(Self::Json),
+            "chrome-trace" => Ok(Self::ChromeTrace),
+            _ => anyhow::bail!("unknown output format: {}", s),
+        }
+    }
+}
+
+/// Where the progress bar is drawn. It is only ever drawn on a terminal.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub(crate) enum ProgressStream {
+    #[default]
+    Stderr,
+    Stdout,
+    None,
+}
+
+impl FromStr for ProgressStream {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "stderr" => Ok(Self::Stderr),
+            "stdout" => Ok(Self::Stdout),
+            "none" => Ok(Self::None),
+            _ => anyhow::bail!(
+                "unknown progress stream: {}; use stderr, stdout, or none",
+                s
+            ),
+        }
+    }
+}
+
+impl ProgressStream {
+    pub(crate) fn draw_target(self) -> ProgressDrawTarget {
+        match self {
+            Self::Stderr => ProgressDrawTarget::stderr(),
+            Self::Stdout => ProgressDrawTarget::stdout(),
+            Self::None => ProgressDrawTarget::hidden(),
+        }
+    }
+}
+
+/// The progress bar shows the field being sent, and advances as each
+/// completes.
+impl RequestObserver for ProgressBar {
+    fn on_start(&self, path: &[String]) {
+        self.set_message(path.join("."));
+    }
+
+    fn on_complete(&self, _path: &[String], _duration: Duration, _status: Status) {
+        self.inc(1);
+    }
+}
+
+/// How results are rolled up for output.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum GroupBy {
+    /// One row per top-level field, covering every field query beneath it.
+    TopLevel,
+}
+
+impl FromStr for GroupBy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "top-level" => Ok(Self::TopLevel),
+            _ => anyhow::bail!("unknown grouping: {}", s),
+        }
+    }
+}
+
+/// A format for each line of human readable output, made up of literal text
+/// and `{placeholder}`s. `{{` and `}}` produce literal braces.
+#[derive(Debug, Clone)]
+pub(crate) struct Template {
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug, Clone)]
+enum Segment {
+    Literal(String),
+    Placeholder(Placeholder),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Placeholder {
+    Bytes,
+    Connection,
+    Duration,
+    ErrorCode,
+    Id,
+    Path,
+    Query,
+    Received,
+    Status,
+    StatusCode,
+}
+
+impl FromStr for Template {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let mut segments = Vec::new();
+        let mut literal = String::new();
+        let mut chars = s.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => anyhow::bail!(
+                                "unterminated placeholder in output template: {{{}",
+                                name
+                            ),
+                        }
+                    }
+                    let placeholder = match name.as_str() {
+                        "bytes" => Placeholder::Bytes,
+                        "connection" => Placeholder::Connection,
+                        "duration" => Placeholder::Duration,
+                        "error_code" => Placeholder::ErrorCode,
+                        "id" => Placeholder::Id,
+                        "path" => Placeholder::Path,
+                        "query" => Placeholder::Query,
+                        "received" => Placeholder::Received,
+                        "status" => Placeholder::Status,
+                        "status_code" => Placeholder::StatusCode,
+                        _ => anyhow::bail!("unknown placeholder in output template: {{{}}}", name),
+                    };
+                    if !literal.is_empty() {
+                        segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                    }
+                    segments.push(Segment::Placeholder(placeholder));
+                }
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+
+        Ok(Self { segments })
+    }
+}
+
+impl Template {
+    fn render(&self, result: &Result) -> String {
+        self.segments
+            .iter()
+            .map(|segment| match segment {
+                Segment::Literal(s) => s.clone(),
+                Segment::Placeholder(Placeholder::Bytes) => result.bytes.to_string(),
+                Segment::Placeholder(Placeholder::Connection) => {
+                    String::from(if result.connection_reused {
+                        "reused"
+                    } else {
+                        "new"
+                    })
+                }
+                Segment::Placeholder(Placeholder::Duration) => {
+                    format!("{:.3}", result.duration.as_secs_f64())
+                }
+                Segment::Placeholder(Placeholder::ErrorCode) => {
+                    result.error_code.clone().unwrap_or_default()
+                }
+                Segment::Placeholder(Placeholder::Id) => result.query.id.clone(),
+                Segment::Placeholder(Placeholder::Path) => result.query.path.join("."),
+                Segment::Placeholder(Placeholder::Query) => result.query.query.clone(),
+                Segment::Placeholder(Placeholder::Received) => {
+                    format!("{:.3}", result.received.as_secs_f64())
+                }
+                Segment::Placeholder(Placeholder::Status) => result.status.to_string(),
+                Segment::Placeholder(Placeholder::StatusCode) => result.status_code.to_string(),
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct OutputOptions {
+    /// List just the path and duration of each result in the human output.
+    pub(crate) fields_only: bool,
+    pub(crate) format: Format,
+    pub(crate) group_by: Option<GroupBy>,
+    /// Indent the JSON output, rather than writing it on a single line.
+    pub(crate) json_pretty: bool,
+    /// Leave the response out of the human output for each failure, relying
+    /// on the error summary instead.
+    pub(crate) quiet: bool,
+    /// Replaces the default human readable line for each result.
+    pub(crate) template: Option<Template>,
+}
+
+pub(crate) fn write(options: &OutputOptions, results: &[Result]) -> anyhow::Result<()> {
+    match (options.format, options.group_by, &options.template) {
+        (_, Some(GroupBy::TopLevel), _) => write_groups(options, results),
+        (Format::Human, None, Some(template)) => {
+            for result in results.iter() {
+                println!("{}", template.render(result));
+            }
+            Ok(())
+        }
+        (Format::Human, None, None) if options.fields_only => write_fields(results),
+        (Format::Human, None, None) => write_human(results, options.quiet),
+        (Format::Json | Format::ChromeTrace, None, _) => write_json(results, options.json_pretty),
+    }
+}
+
+/// The aggregate timings of every query sent at a single concurrency level.
+#[derive(Debug, Serialize)]
+pub(crate) struct SweepLevel {
+    concurrency: usize,
+    queries: usize,
+    #[serde(serialize_with = "serialize_seconds")]
+    mean: Duration,
+    #[serde(serialize_with = "serialize_seconds")]
+    p95: Duration,
+    /// Successful queries per second of wall clock time.
+    throughput: f64,
+}
+
+impl SweepLevel {
+    /// Aggregates the durations of every query sent at the level, of which
+    /// the given number succeeded, over the given wall clock time.
+    pub(crate) fn new(
+        concurrency: usize,
+        mut durations: Vec<Duration>,
+        succeeded: usize,
+        wall: Duration,
+    ) -> Self {
+        durations.sort();
+        let queries = durations.len();
+        let mean = match queries {
+            0 => Duration::ZERO,
+            n => durations.iter().sum::<Duration>() / n as u32,
+        };
+        // The nearest-rank percentile.
+        let p95 = match queries {
+            0 => Duration::ZERO,
+            n => durations[(n * 95).div_ceil(100) - 1],
+        };
+
+        Self {
+            concurrency,
+            queries,
+            mean,
+            p95,
+            // Counted as in the summary, so that the two agree.
+            throughput: if wall.is_zero() {
+                0.0
+            } else {
+                succeeded as f64 / wall.as_secs_f64()
+            },
+        }
+    }
+}
+
+/// Writes a row for each concurrency level of a sweep.
+pub(crate) fn write_sweep(options: &OutputOptions, levels: &[SweepLevel]) -> anyhow::Result<()> {
+    match options.format {
+        Format::Human => {
+            println!(
+                "{}",
+                style(format!(
+                    "{:>11}  {:>7}  {:>8}  {:>8}  {:>10}",
+                    "concurrency", "queries", "mean", "p95", "queries/s"
+                ))
+                .bold()
+            );
+            for level in levels.iter() {
+                println!(
+                    "{:>11}  {:>7}  {:>7.3}s  {:>7.3}s  {:>10.1}",
+                    level.concurrency,
+                    level.queries,
+                    level.mean.as_secs_f64(),
+                    level.p95.as_secs_f64(),
+                    level.throughput
+                );
+            }
+        }
+        Format::Json | Format::ChromeTrace => println!("{}", to_json(levels, options.json_pretty)?),
+    }
+
+    Ok(())
+}
+
+fn serialize_seconds<S: serde::Serializer>(
+    duration: &Duration,
+    s: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    s.serialize_f64(duration.as_secs_f64())
+}
+
+/// Writes the number of field queries, in total and per top-level field.
+pub(crate) fn write_count(format: Format, queries: &[FieldQuery]) -> anyhow::Result<()> {
+    let mut by_field: BTreeMap<&str, usize> = BTreeMap::new();
+    for query in queries.iter() {
+        if let Some(field) = query.path.first() {
+            *by_field.entry(field).or_default() += 1;
+        }
+    }
+
+    match format {
+        Format::Human => {
+            println!("{} field queries", queries.len());
+            let width = by_field.keys().map(|field| field.len()).max().unwrap_or(0);
+            for (field, count) in by_field.iter() {
+                println!("  {:width$}  {}", field, count, width = width);
+            }
+        }
+        Format::Json | Format::ChromeTrace => println!(
+            "{}",
+            json!({ "total": queries.len(), "by_top_level_field": by_field })
+        ),
+    }
+
+    Ok(())
+}
+
+/// Writes the field queries that failed validation against the schema, with
+/// the reasons for each, followed by how many were valid.
+pub(crate) fn write_validation(
+    format: Format,
+    queries: &[FieldQuery],
+    problems: &[Vec<String>],
+) -> anyhow::Result<()> {
+    let invalid: Vec<_> = queries
+        .iter()
+        .zip(problems.iter())
+        .filter(|(_, problems)| !problems.is_empty())
+        .collect();
+
+    match format {
+        Format::Human => {
+            for (query, problems) in invalid.iter() {
+                println!("{} {}", style("INVALID").red(), query.path.join("."));
+                for problem in problems.iter() {
+                    println!("  {}", problem);
+                }
+            }
+            println!(
+                "{} of {} field queries are valid",
+                queries.len() - invalid.len(),
+                queries.len()
+            );
+        }
+        Format::Json | Format::ChromeTrace => println!(
+            "{}",
+            json!({
+                "total": queries.len(),
+                "valid": queries.len() - invalid.len(),
+                "invalid": invalid
+                    .iter()
+                    .map(|(query, problems)| json!({
+                        "id": query.id,
+                        "path": query.path,
+                        "query": query.query,
+                        "problems": problems,
+                    }))
+                    .collect::<Vec<_>>(),
+            })
+        ),
+    }
+
+    Ok(())
+}
+
+/// Writes a summary of any field queries that couldn't be generated to stderr,
+/// so that it doesn't interfere with the results.
+pub(crate) fn write_skipped(skipped: &[SkippedQuery]) {
+    if skipped.is_empty() {
+        return;
+    }
+
+    eprintln!(
+        "{}",
+        style(format!(
+            "Skipped {} field queries that could not be generated:",
+            skipped.len()
+        ))
+        .yellow()
+    );
+    for query in skipped.iter() {
+        eprintln!("  {}: {}", query.path.join("."), query.reason);
+    }
+}
+
+/// Writes the field queries that had no matching request in a HAR file to
+/// stderr.
+pub(crate) fn write_unmatched(unmatched: &[&FieldQuery]) {
+    if unmatched.is_empty() {
+        return;
+    }
+
+    eprintln!(
+        "{}",
+        style(format!(
+            "No request in the HAR file matched {} field queries:",
+            unmatched.len()
+        ))
+        .yellow()
+    );
+    for query in unmatched.iter() {
+        eprintln!("  {}", query.path.join("."));
+    }
+}
+
+/// Writes each distinct error among the failures to stderr, along with the
+/// number of failures that had it and the path of one of them, most common
+/// first. Errors are distinguished by their message and code.
+pub(crate) fn write_errors(results: &[Result]) {
+    let mut errors = BTreeMap::new();
+    for result in results.iter().filter(|r| r.status == Status::Failure) {
+        errors
+            .entry((result.error_message(), result.error_code.as_deref()))
+            .or_insert((0, result))
+            .0 += 1;
+    }
+    if errors.is_empty() {
+        return;
+    }
+
+    eprintln!(
+        "{}",
+        style(format!(
+            "{} failed field queries, by error:",
+            errors.values().map(|(count, _)| count).sum::<usize>()
+        ))
+        .red()
+    );
+    let width = errors
+        .values()
+        .map(|(count, _)| count.to_string().len())
+        .max()
+        .unwrap_or(0);
+    for ((message, code), (count, example)) in
+        errors.iter().sorted_by(|(_, (a, _)), (_, (b, _))| b.cmp(a))
+    {
+        eprintln!(
+            "  {:>width$}  {}{} {}",
+            count,
+            match code {
+                Some(code) => format!("{} ", render_error_code(code)),
+                None => String::new(),
+            },
+            message.as_deref().unwrap_or("(no message)"),
+            style(format!("eg {}", example.query.path.join("."))).dim(),
+            width = width
+        );
+    }
+}
+
+/// Writes a single line summarising the run to stderr, in a fixed format that
+/// scripts can parse regardless of the output format. Successful responses
+/// that also contain errors are counted as partial. Throughput counts every
+/// sample of the queries that succeeded, per second of wall clock time.
+/// Failures are also tallied by error code, if any had one, and the retries
+/// made are counted if retries were enabled.
+pub(crate) fn write_summary(results: &[Result], wall: Duration, retries: Option<RetryUsage>) {
+    let (mut ok, mut partial, mut fail) = (0, 0, 0);
+    let mut codes: BTreeMap<&str, usize> = BTreeMap::new();
+    for result in results.iter() {
+        match result.status {
+            Status::Success if result.has_errors() => partial += 1,
+            Status::Success => ok += 1,
+            Status::Failure => {
+                fail += 1;
+                if let Some(code) = result.error_code.as_deref() {
+                    *codes.entry(code).or_default() += 1;
+                }
+            }
+        }
+    }
+    let total: Duration = results.iter().flat_map(|r| r.samples.iter()).sum();
+    let succeeded: usize = results
+        .iter()
+        .filter(|r| r.status == Status::Success)
+        .map(|r| r.samples.len())
+        .sum();
+    let qps = if wall.is_zero() {
+        0.0
+    } else {
+        succeeded as f64 / wall.as_secs_f64()
+    };
+
+    eprintln!(
+        "SUMMARY ok={} partial={} fail={} total_seconds={:.3} wall_seconds={:.3} qps={:.1}{}{}",
+        ok,
+        partial,
+        fail,
+        total.as_secs_f64(),
+        wall.as_secs_f64(),
+        qps,
+        match retries {
+            Some(RetryUsage {
+                used,
+                budget: Some(budget),
+            }) => format!(" retries={} retry_budget={}", used, budget),
+            Some(RetryUsage { used, budget: None }) => format!(" retries={}", used),
+            None => String::new(),
+        },
+        if codes.is_empty() {
+            String::new()
+        } else {
+            format!(
+                " error_codes={}",
+                codes
+                    .iter()
+                    .map(|(code, count)| format!("{}:{}", code, count))
+                    .join(",")
+            )
+        }
+    );
+}
+
+fn write_human(results: &[Result], quiet: bool) -> anyhow::Result<()> {
+    for result in results.iter() {
+        println!(
+            "{}{} {} {}",
+            render_status(result.status),
+            match result.error_code.as_deref() {
+                Some(code) if result.status == Status::Failure => {
+                    format!(" {}", render_error_code(code))
+                }
+                _ => String::new(),
+            },
+            style(format!(
+                " {:.3}s {}{}{} ",
+                result.duration.as_secs_f64(),
+                result.status_code,
+                match result.chunks.last() {
+                    Some(last) => format!(
+                        " ({} parts, last at {:.3}s)",
+                        result.chunks.len(),
+                        last.as_secs_f64()
+                    ),
+                    None => String::new(),
+                },
+                match result.cache_probe {
+                    Some(probe) => format!(
+                        " (cold {:.3}s, warm {:.3}s)",
+                        probe.cold.as_secs_f64(),
+                        probe.warm.as_secs_f64()
+                    ),
+                    None => String::new(),
+                },
+            ))
+            .dim(),
+            result.query,
+        );
+        if let Some(explanation) = &result.explanation {
+            println!("{}", style(explanation).dim());
+        }
+        if result.status == Status::Failure && !quiet {
+            println!("{}", result.dump_response());
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes the path to each field and its duration, with the paths aligned.
+fn write_fields(results: &[Result]) -> anyhow::Result<()> {
+    let paths = results
+        .iter()
+        .map(|result| result.query.path.join("."))
+        .collect_vec();
+    let width = paths.iter().map(String::len).max().unwrap_or(0);
+    for (result, path) in results.iter().zip(paths.iter()) {
+        println!(
+            "{:width$} {:.3}s{}",
+            path,
+            result.duration.as_secs_f64(),
+            match result.status {
+                Status::Success => String::new(),
+                Status::Failure => format!(" {}", render_status(result.status)),
+            },
+            width = width
+        );
+        if let Some(explanation) = &result.explanation {
+            println!("{:width$} {}", "", style(explanation).dim(), width = width);
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes a JSON Schema for the results written by `--format json`. This
+/// mirrors the `Serialize` impls of [`Result`] and the [`FieldQuery`] and
+/// response flattened into it, and must be kept in step with them.
+pub(crate) fn write_output_schema() -> anyhow::Result<()> {
+    println!("{}", serde_json::to_string_pretty(&output_schema())?);
+    Ok(())
+}
+
+fn output_schema() -> serde_json::Value {
+    let seconds = json!({ "type": "number", "minimum": 0 });
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "graphql-field-timer results",
+        "description": "The results of a run, one per field query, sorted by duration.",
+        "type": "array",
+        "items": {
+            "type": "object",
+            "additionalProperties": false,
+            "required": [
+                "bytes", "connection_reused", "duration", "id", "path", "query",
+                "received", "samples", "status", "status_code", "tags",
+            ],
+            "properties": {
+                "bytes": {
+                    "description": "Size of the response body in bytes.",
+                    "type": "integer",
+                    "minimum": 0,
+                },
+                "cache_probe": {
+                    "description": "Durations of the query sent cold and then warm, with --cache-probe.",
+                    "type": "object",
+                    "required": ["cold", "warm"],
+                    "properties": { "cold": seconds, "warm": seconds },
+                },
+                "chunks": {
+                    "description": "Seconds at which each part of an incremental response arrived.",
+                    "type": "array",
+                    "items": seconds,
+                },
+                "connection_reused": {
+                    "description": "Whether every sample was sent on an already open connection.",
+                    "type": "boolean",
+                },
+                "data": {
+                    "description": "The response data, with --capture-data.",
+                },
+                "duration": {
+                    "description": "Median seconds until the response headers arrived.",
+                    "type": "number",
+                    "minimum": 0,
+                },
+                "endpoint": {
+                    "description": "URL the query was sent to, if set by the endpoint directive.",
+                    "type": "string",
+                },
+                "error_code": {
+                    "description": "The extensions.code of the first error in the response.",
+                    "type": "string",
+                },
+                "errors": {
+                    "description": "The errors from the response, as the server sent them.",
+                },
+                "explanation": {
+                    "description": "Why the response was classified as it was, with --explain.",
+                    "type": "string",
+                },
+                "id": {
+                    "description": "Stable identifier derived from the query text.",
+                    "type": "string",
+                },
+                "path": {
+                    "description": "Response keys leading to the field.",
+                    "type": "array",
+                    "items": { "type": "string" },
+                },
+                "query": { "type": "string" },
+                "received": {
+                    "description": "Median seconds until the full response body arrived.",
+                    "type": "number",
+                    "minimum": 0,
+                },
+                "samples": {
+                    "description": "Seconds until the response headers arrived, per repeat.",
+                    "type": "array",
+                    "items": seconds,
+                },
+                "started": {
+                    "description": "When the first sample was started, with --timestamps.",
+                    "type": "string",
+                    "format": "date-time",
+                },
+                "status": { "enum": ["success", "failure"] },
+                "status_code": {
+                    "description": "HTTP status code, or 0 if no response was received.",
+                    "type": "integer",
+                },
+                "tags": {
+                    "description": "Arguments of the tag directive on the field and above it.",
+                    "type": "object",
+                    "additionalProperties": { "type": "string" },
+                },
+                "upload": {
+                    "description": "Seconds taken to upload a streamed request body, with --stream-body.",
+                    "type": "number",
+                    "minimum": 0,
+                },
+            },
+        },
+    })
+}
+
+fn write_json(results: &[Result], pretty: bool) -> anyhow::Result<()> {
+    println!("{}", to_json(results, pretty)?);
+    Ok(())
+}
+
+fn to_json<T: Serialize + ?Sized>(value: &T, pretty: bool) -> serde_json::Result<String> {
+    if pretty {
+        serde_json::to_string_pretty(value)
+    } else {
+        serde_json::to_string(value)
+    }
+}
+
+/// Writes the total and mean duration of the results under each top-level
+/// field, slowest first.
+fn write_groups(options: &OutputOptions, results: &[Result]) -> anyhow::Result<()> {
+    let mut groups: BTreeMap<&str, (Duration, u32)> = BTreeMap::new();
+    for result in results.iter() {
+        if let Some(field) = result.query.path.first() {
+            let (total, count) = groups.entry(field).or_default();
+            *total += result.duration;
+            *count += 1;
+        }
+    }
+    let groups = groups
+        .into_iter()
+        .map(|(field, (total, count))| (field, total, total / count, count))
+        .sorted_by(|a, b| b.1.cmp(&a.1))
+        .collect_vec();
+
+    match options.format {
+        Format::Human => {
+            let width = groups.iter().map(|g| g.0.len()).max().unwrap_or(0);
+            for (field, total, mean, count) in groups.iter() {
+                println!(
+                    "{:width$}  {} {}",
+                    field,
+                    style(format!(
+                        "total {:.3}s  mean {:.3}s",
+                        total.as_secs_f64(),
+                        mean.as_secs_f64()
+                    ))
+                    .bold(),
+                    style(format!("({} fields)", count)).dim(),
+                    width = width
+                );
+            }
+        }
+        Format::Json | Format::ChromeTrace => println!(
+            "{}",
+            to_json(
+                &groups
+                    .iter()
+                    .map(|(field, total, mean, count)| json!({
+                        "group": field,
+                        "total": total.as_secs_f64(),
+                        "mean": mean.as_secs_f64(),
+                        "count": count,
+                    }))
+                    .collect_vec(),
+                options.json_pretty
+            )?
+        ),
+    }
+
+    Ok(())
+}
+
+pub(crate) fn render_status(status: Status) -> StyledObject<String> {
+    match status {
+        Status::Success => style(" OK  ".into()).black().on_green(),
+        Status::Failure => style(" ERR ".into()).white().on_red(),
+    }
+    .bright()
+    .bold()
+}
+
+/// Colours an error code by its category, so that authentication problems,
+/// rate limiting, and server bugs can be told apart at a glance.
+pub(crate) fn render_error_code(code: &str) -> StyledObject<&str> {
+    let styled = style(code).bold();
+    match code {
+        "UNAUTHENTICATED" | "FORBIDDEN" => styled.magenta(),
+        "RATE_LIMITED" | "TOO_MANY_REQUESTS" | "THROTTLED" => styled.yellow(),
+        "BAD_USER_INPUT" | "GRAPHQL_VALIDATION_FAILED" | "GRAPHQL_PARSE_FAILED" => styled.cyan(),
+        _ => styled.red(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timer::CacheProbe;
+
+    #[test]
+    fn sweep_throughput_counts_successes() {
+        let durations: Vec<_> = (1..=4).map(Duration::from_millis).collect();
+        let level = SweepLevel::new(2, durations.clone(), 3, Duration::from_secs(2));
+        assert_eq!(level.queries, 4);
+        assert_eq!(level.throughput, 1.5);
+        assert_eq!(level.p95, Duration::from_millis(4));
+
+        let level = SweepLevel::new(2, durations, 3, Duration::ZERO);
+        assert_eq!(level.throughput, 0.0);
+    }
+
+    #[test]
+    fn output_schema_covers_every_key() {
+        let query = FieldQuery {
+            id: "id".into(),
+            query: "{ a }".into(),
+            path: vec!["a".into()],
+            tags: BTreeMap::from([("team".into(), "core".into())]),
+            endpoint: Some("https://example.com/graphql".into()),
+            lines: vec![1],
+            read_only: true,
+            arg_template: None,
+        };
+        let ms = Duration::from_millis;
+        let mut result = Result::recorded(
+            &query,
+            ms(10),
+            ms(20),
+            true,
+            200,
+            r#"{"data":{"a":1},"errors":[{"message":"partial"}]}"#,
+        )
+        .unwrap();
+        result.cache_probe = Some(CacheProbe {
+            cold: ms(30),
+            warm: ms(10),
+        });
+        result.chunks = vec![ms(15)];
+        result.data = Some(json!({ "a": 1 }));
+        result.error_code = Some("PARTIAL".into());
+        result.explanation = Some("data present".into());
+        result.started = Some(std::time::SystemTime::now());
+        result.upload = Some(ms(5));
+
+        let keys = match serde_json::to_value(&result).unwrap() {
+            serde_json::Value::Object(object) => object.keys().cloned().collect_vec(),
+            other => panic!("not an object: {}", other),
+        };
+        let schema = output_schema();
+        let properties = schema["items"]["properties"]
+            .as_object()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect_vec();
+        assert_eq!(keys, properties);
+    }
+}
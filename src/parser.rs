@@ -1,8 +1,10 @@
 use std::{
     collections::BTreeMap,
     fmt::{Debug, Display},
+    str::FromStr,
 };
 
+use anyhow::Context;
 use graphql_parser::{
     query::{
         Definition, Document, Field, FragmentDefinition, FragmentSpread, InlineFragment,
@@ -11,13 +13,349 @@ use graphql_parser::{
     schema::{Directive, Text, Value},
 };
 use itertools::Itertools;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
 
-pub(crate) fn parse_document<'a, T>(doc: &'a Document<'a, T>) -> anyhow::Result<Vec<String>>
+/// A single field query generated from a document, along with the metadata
+/// collected on the way down to the field.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct FieldQuery {
+    /// A stable identifier derived from the query text, which can be used to
+    /// join results across runs.
+    pub(crate) id: String,
+    pub(crate) query: String,
+    /// The response keys leading to the field, taking aliases into account.
+    pub(crate) path: Vec<String>,
+    pub(crate) tags: BTreeMap<String, String>,
+    /// URL to send the query to instead of the default, set by the endpoint
+    /// directive on the field or anything above it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) endpoint: Option<String>,
+    /// The source line on which each definition and selection leading to the
+    /// field starts, including through fragment spreads.
+    #[serde(skip)]
+    pub(crate) lines: Vec<usize>,
+    /// Whether the operation is a query, which can safely be sent again.
+    /// Mutations and subscriptions can only be replayed, never decomposed.
+    #[serde(skip)]
+    pub(crate) read_only: bool,
+    /// The query with the arguments that vary across repeats cut out, if any
+    /// do.
+    #[serde(skip)]
+    pub(crate) arg_template: Option<ArgTemplate>,
+}
+
+impl Display for FieldQuery {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.query)
+    }
+}
+
+/// The field queries generated from a document.
+#[derive(Debug, Default)]
+pub(crate) struct Decomposition {
+    pub(crate) queries: Vec<FieldQuery>,
+    /// Fields whose query could not be regenerated, and so won't be timed.
+    pub(crate) skipped: Vec<SkippedQuery>,
+}
+
+#[derive(Debug)]
+pub(crate) struct SkippedQuery {
+    pub(crate) path: Vec<String>,
+    pub(crate) reason: String,
+}
+
+#[derive(Debug)]
+pub(crate) struct ParseOptions {
+    /// Select __typename in every selection set below the operation, as
+    /// Apollo Client does, without timing it as a field of its own.
+    pub(crate) add_typename: bool,
+    /// Directive whose url argument routes the queries for the fields beneath
+    /// it to another endpoint. The directive is stripped from the generated
+    /// queries.
+    pub(crate) endpoint_directive: String,
+    /// Canonicalise each generated query, so that equivalent documents
+    /// formatted differently produce the same query text and id.
+    pub(crate) normalize: bool,
+    /// Name given to every generated operation, replacing the name of the
+    /// operation it was decomposed from.
+    pub(crate) operation_name: Option<String>,
+    /// Directive marking fields, along with everything beneath them, that
+    /// shouldn't be timed.
+    pub(crate) ignore_directive: String,
+    /// Directive whose arguments become tags on the generated field queries.
+    /// The directive itself is stripped from the generated queries.
+    pub(crate) tag_directive: String,
+}
+
+impl ParseOptions {
+    fn is_internal_directive(&self, name: &str) -> bool {
+        name == self.tag_directive
+            || name == self.ignore_directive
+            || name == self.endpoint_directive
+    }
+
+    fn is_ignored<'a, T>(&self, dirs: &[Directive<'a, T>]) -> bool
+    where
+        T: Text<'a>,
+    {
+        dirs.iter()
+            .any(|dir| dir.name.as_ref() == self.ignore_directive)
+    }
+}
+
+/// An integer argument whose value is varied across repeats, parsed from
+/// `name=start..end`.
+#[derive(Debug, Clone)]
+pub(crate) struct ArgOverride {
+    name: String,
+    start: i32,
+    end: i32,
+}
+
+impl ArgOverride {
+    fn value(&self, repeat: usize) -> i32 {
+        // Widened, since the length of a range spanning most of i32 doesn't
+        // fit in one.
+        let len = (i64::from(self.end) - i64::from(self.start)) as u64 + 1;
+        (i64::from(self.start) + (repeat as u64 % len) as i64) as i32
+    }
+}
+
+/// A query with the values of its overridden arguments cut out, so that the
+/// values for each repeat can be filled in without parsing it again.
+#[derive(Debug, Clone)]
+pub(crate) struct ArgTemplate {
+    // The text around the values, with one more part than there are values.
+    parts: Vec<String>,
+    // The override giving each value, in the order they appear.
+    slots: Vec<ArgOverride>,
+}
+
+impl ArgTemplate {
+    /// Returns the query with the values for the given repeat filled in.
+    pub(crate) fn render(&self, repeat: usize) -> String {
+        let mut query = self.parts[0].clone();
+        for (slot, part) in self.slots.iter().zip(self.parts[1..].iter()) {
+            query.push_str(&slot.value(repeat).to_string());
+            query.push_str(part);
+        }
+        query
+    }
+}
+
+impl FromStr for ArgOverride {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (name, range) = match s.split_once('=') {
+            Some(parts) => parts,
+            None => anyhow::bail!("argument override must be NAME=START..END: {}", s),
+        };
+        let (start, end) = match range.split_once("..") {
+            Some((start, end)) => (start.trim().parse()?, end.trim().parse()?),
+            None => anyhow::bail!("argument override must be NAME=START..END: {}", s),
+        };
+        if end < start {
+            anyhow::bail!("argument override range is empty: {}", s);
+        }
+
+        Ok(Self {
+            name: name.trim().to_string(),
+            start,
+            end,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct Path {
+    segments: Vec<String>,
+    fields: Vec<String>,
+    tags: BTreeMap<String, String>,
+    endpoint: Option<String>,
+    lines: Vec<usize>,
+    // Names of the fragments being expanded, so that a fragment that spreads
+    // itself can be caught rather than recursing forever.
+    spreads: Vec<String>,
+}
+
+impl Path {
+    fn to_field_query(&self, options: &ParseOptions) -> anyhow::Result<FieldQuery> {
+        let query = path_to_query(&self.segments, options)?;
+        Ok(FieldQuery {
+            id: query_id(&query),
+            query,
+            path: self.fields.clone(),
+            tags: self.tags.clone(),
+            endpoint: self.endpoint.clone(),
+            lines: self.lines.clone(),
+            read_only: true,
+            arg_template: None,
+        })
+    }
+
+    /// Routes the fields beneath to the URL given by the endpoint directive,
+    /// if present. The URL is checked here so that a bad one is caught before
+    /// any queries are sent.
+    fn set_endpoint<'a, T>(
+        &mut self,
+        dirs: &[Directive<'a, T>],
+        options: &ParseOptions,
+    ) -> anyhow::Result<()>
+    where
+        T: Text<'a> + Debug,
+        T::Value: Display + Debug,
+    {
+        let dir = match dirs
+            .iter()
+            .find(|dir| dir.name.as_ref() == options.endpoint_directive)
+        {
+            Some(dir) => dir,
+            None => return Ok(()),
+        };
+
+        let url = match dir
+            .arguments
+            .iter()
+            .find(|(name, _)| name.as_ref() == "url")
+        {
+            Some((_, Value::String(url))) => url,
+            _ => anyhow::bail!(
+                "@{} must have a string url argument",
+                options.endpoint_directive
+            ),
+        };
+        let uri = hyper::Uri::from_str(url)
+            .with_context(|| format!("invalid @{} url: {}", options.endpoint_directive, url))?;
+        if uri.scheme().is_none() || uri.host().is_none() {
+            anyhow::bail!(
+                "@{} url must be absolute, eg https://host/graphql: {}",
+                options.endpoint_directive,
+                url
+            );
+        }
+
+        self.endpoint = Some(url.clone());
+        Ok(())
+    }
+
+    fn add_tags<'a, T>(&mut self, dirs: &[Directive<'a, T>], options: &ParseOptions)
+    where
+        T: Text<'a> + Debug,
+        T::Value: Display + Debug,
+    {
+        for dir in dirs
+            .iter()
+            .filter(|dir| dir.name.as_ref() == options.tag_directive)
+        {
+            for (name, value) in dir.arguments.iter() {
+                self.tags.insert(
+                    name.to_string(),
+                    match value {
+                        Value::String(s) => s.clone(),
+                        value => value.to_string(),
+                    },
+                );
+            }
+        }
+    }
+}
+
+/// Extracts variables embedded in the document, so that a query and its
+/// variables can be kept in one file. They may be given in a frontmatter block
+/// delimited by lines of `---` at the very start of the document, or on a
+/// single `# variables:` comment line before the first definition. The
+/// frontmatter is blanked out rather than removed, so that line numbers in
+/// parse errors still match the file.
+pub(crate) fn split_frontmatter(raw: &str) -> anyhow::Result<(String, Option<String>)> {
+    let mut lines = raw.split_inclusive('\n');
+    if lines.next().map(str::trim_end) == Some("---") {
+        let mut variables = String::new();
+        let mut blanked = String::from("\n");
+        for line in lines.by_ref() {
+            blanked.push('\n');
+            if line.trim_end() == "---" {
+                return Ok((blanked + &lines.collect::<String>(), Some(variables)));
+            }
+            variables.push_str(line);
+        }
+        anyhow::bail!("the frontmatter at the start of the document has no closing ---");
+    }
+
+    let variables = raw
+        .lines()
+        .map(str::trim)
+        .take_while(|line| line.is_empty() || line.starts_with('#'))
+        .find_map(|line| {
+            line.trim_start_matches('#')
+                .trim()
+                .strip_prefix("variables:")
+        })
+        .map(|variables| variables.trim().to_string());
+    Ok((raw.to_string(), variables))
+}
+
+/// Describes an error from parsing the document, pointing at the offending
+/// line of the source. The parser only reports the position within its
+/// message, so it's extracted from there. Documents not read from a file are
+/// described by byte offset.
+pub(crate) fn describe_parse_error(
+    raw: &str,
+    error: &graphql_parser::query::ParseError,
+    file: Option<&std::path::Path>,
+) -> anyhow::Error {
+    let message = error.to_string();
+    let (header, detail) = message.split_once('\n').unwrap_or((&message, ""));
+    let position = header
+        .rsplit_once(" at ")
+        .and_then(|(_, position)| position.split_once(':'))
+        .and_then(|(line, column)| Some((line.parse::<usize>().ok()?, column.parse().ok()?)));
+    let (line, column) = match position {
+        Some((line, column)) if line > 0 && column > 0 => (line, column),
+        _ => return anyhow::anyhow!("{}", message),
+    };
+
+    let source = raw.lines().nth(line - 1).unwrap_or_default();
+    let offset = raw
+        .split_inclusive('\n')
+        .take(line - 1)
+        .map(str::len)
+        .sum::<usize>()
+        + source
+            .chars()
+            .take(column - 1)
+            .map(char::len_utf8)
+            .sum::<usize>();
+    let location = match file {
+        Some(file) => format!("{}:{}:{}", file.display(), line, column),
+        None => format!("byte offset {} (line {}, column {})", offset, line, column),
+    };
+    let gutter = line.to_string().len();
+
+    anyhow::anyhow!(
+        "error parsing the query at {}\n{:gutter$} |\n{} | {}\n{:gutter$} | {:>column$}\n{}",
+        location,
+        "",
+        line,
+        source,
+        "",
+        "^",
+        detail,
+        gutter = gutter,
+        column = column
+    )
+}
+
+pub(crate) fn parse_document<'a, T>(
+    doc: &'a Document<'a, T>,
+    options: &ParseOptions,
+) -> anyhow::Result<Decomposition>
 where
     T: Text<'a> + Debug,
     T::Value: Display + Debug,
 {
-    let mut field_queries = Vec::new();
+    let mut decomposition = Decomposition::default();
     let fragments: BTreeMap<String, &FragmentDefinition<'_, T>> = doc
         .definitions
         .iter()
@@ -27,60 +365,101 @@ where
         })
         .collect();
 
-    for query in doc.definitions.iter().filter_map(|def| match def {
-        Definition::Operation(OperationDefinition::Query(query)) => Some(query),
-        _ => None,
-    }) {
-        handle_query(query, &mut field_queries, &fragments)?;
+    for def in doc.definitions.iter() {
+        match def {
+            Definition::Operation(OperationDefinition::Query(query)) => {
+                handle_query(query, &mut decomposition, &fragments, options).with_context(|| {
+                    match &query.name {
+                        Some(name) => format!("cannot decompose query {}", name),
+                        None => String::from("cannot decompose anonymous query"),
+                    }
+                })?
+            }
+            Definition::Operation(OperationDefinition::SelectionSet(ss)) => {
+                // The query shorthand is equivalent to an anonymous query with
+                // no variables or directives.
+                let mut path = Path::default();
+                path.segments.push(format!(
+                    "query {}",
+                    options.operation_name.as_deref().unwrap_or_default()
+                ));
+                path.lines.push(ss.span.0.line);
+                handle_selection_set(&path, ss, &mut decomposition, &fragments, options)
+                    .context("cannot decompose anonymous query")?
+            }
+            _ => {}
+        }
     }
 
-    Ok(field_queries)
+    Ok(decomposition)
 }
 
 fn handle_query<'a, 'b, T>(
     query: &Query<'a, T>,
-    field_queries: &mut Vec<String>,
+    decomposition: &mut Decomposition,
     fragments: &'b BTreeMap<String, &FragmentDefinition<'a, T>>,
+    options: &ParseOptions,
 ) -> anyhow::Result<()>
 where
     T: Text<'a> + Debug,
     T::Value: Display + Debug,
 {
+    // Every variable definition is carried over here; those that the
+    // generated query doesn't use are pruned once it's built.
+    let mut path = Path::default();
+    path.segments.push(format!(
+        "query {}{} {}",
+        options
+            .operation_name
+            .clone()
+            .or_else(|| query.name.as_ref().map(|s| s.to_string()))
+            .unwrap_or_default(),
+        variable_definitions_to_str(&query.variable_definitions),
+        directives_to_str(&query.directives, options),
+    ));
+    path.lines.push(query.position.line);
+    path.add_tags(&query.directives, options);
+    path.set_endpoint(&query.directives, options)?;
+
     handle_selection_set(
-        &Vec::from([format!(
-            "query {}({}) {}",
-            query
-                .name
-                .as_ref()
-                .map(|s| s.to_string())
-                .unwrap_or_default(),
-            variable_definitions_to_str(&query.variable_definitions),
-            directives_to_str(&query.directives),
-        )]),
+        &path,
         &query.selection_set,
-        field_queries,
+        decomposition,
         fragments,
+        options,
     )
 }
 
 fn handle_selection_set<'a, 'b, T>(
-    path: &[String],
+    path: &Path,
     ss: &SelectionSet<'a, T>,
-    field_queries: &mut Vec<String>,
+    decomposition: &mut Decomposition,
     fragments: &'b BTreeMap<String, &FragmentDefinition<'a, T>>,
+    options: &ParseOptions,
 ) -> anyhow::Result<()>
 where
     T: Text<'a> + Debug,
     T::Value: Display + Debug,
 {
     for item in ss.items.iter() {
+        let directives = match item {
+            Selection::Field(field) => &field.directives,
+            Selection::FragmentSpread(spread) => &spread.directives,
+            Selection::InlineFragment(fragment) => &fragment.directives,
+        };
+        if options.is_ignored(directives) {
+            continue;
+        }
+
         match item {
-            Selection::Field(field) => handle_field(path, field, field_queries, fragments)?,
+            Selection::Field(field) => {
+                handle_field(path, field, decomposition, fragments, options)?
+            }
             Selection::FragmentSpread(spread) => {
-                handle_fragment_spread(path, spread, field_queries, fragments)?
+                handle_fragment_spread(path, spread, decomposition, fragments, options)?
             }
             Selection::InlineFragment(fragment) => {
-                handle_inline_fragment(path, fragment, field_queries, fragments)?
+                handle_inline_fragment(path, fragment, decomposition, fragments, options)?
             }
         }
     }
@@ -89,17 +468,18 @@ where
 }
 
 fn handle_field<'a, 'b, T>(
-    path: &[String],
+    path: &Path,
     field: &Field<'a, T>,
-    field_queries: &mut Vec<String>,
+    decomposition: &mut Decomposition,
     fragments: &'b BTreeMap<String, &FragmentDefinition<'a, T>>,
+    options: &ParseOptions,
 ) -> anyhow::Result<()>
 where
     T: Text<'a> + Debug,
     T::Value: Display + Debug,
 {
-    let mut path = Vec::from(path);
-    path.push(format!(
+    let mut path = path.clone();
+    path.segments.push(format!(
         "{}{}{} {}",
         field
             .alias
@@ -108,24 +488,42 @@ where
             .unwrap_or_default(),
         field.name,
         arguments_to_str(&field.arguments),
-        directives_to_str(&field.directives),
+        directives_to_str(&field.directives, options),
     ));
+    path.fields
+        .push(field.alias.as_ref().unwrap_or(&field.name).to_string());
+    path.lines.push(field.position.line);
+    path.add_tags(&field.directives, options);
+    path.set_endpoint(&field.directives, options)?;
 
     if field.selection_set.items.is_empty() {
         // Leaf node; handle accordingly.
-        field_queries.push(path_to_query(&path)?);
+        match path.to_field_query(options) {
+            Ok(query) => decomposition.queries.push(query),
+            Err(e) => decomposition.skipped.push(SkippedQuery {
+                path: path.fields.clone(),
+                reason: e.to_string(),
+            }),
+        }
     } else {
-        handle_selection_set(&path, &field.selection_set, field_queries, fragments)?;
+        handle_selection_set(
+            &path,
+            &field.selection_set,
+            decomposition,
+            fragments,
+            options,
+        )?;
     }
 
     Ok(())
 }
 
 fn handle_fragment_spread<'a, 'b, T>(
-    path: &[String],
+    path: &Path,
     spread: &FragmentSpread<'a, T>,
-    field_queries: &mut Vec<String>,
+    decomposition: &mut Decomposition,
     fragments: &'b BTreeMap<String, &FragmentDefinition<'a, T>>,
+    options: &ParseOptions,
 ) -> anyhow::Result<()>
 where
     T: Text<'a> + Debug,
@@ -139,48 +537,216 @@ where
         ),
     };
 
-    let mut path = Vec::from(path);
-    path.push(format!(
+    let name = spread.fragment_name.to_string();
+    if path.spreads.contains(&name) {
+        anyhow::bail!("fragment {} spreads itself", name);
+    }
+
+    let mut path = path.clone();
+    path.spreads.push(name);
+    path.segments.push(format!(
         "... {} {}",
         fragment.type_condition,
-        directives_to_str(&fragment.directives)
+        directives_to_str(&fragment.directives, options)
     ));
+    path.lines.push(spread.position.line);
+    path.lines.push(fragment.position.line);
+    path.add_tags(&fragment.directives, options);
+    path.set_endpoint(&fragment.directives, options)?;
 
-    handle_selection_set(&path, &fragment.selection_set, field_queries, fragments)
+    handle_selection_set(
+        &path,
+        &fragment.selection_set,
+        decomposition,
+        fragments,
+        options,
+    )
 }
 
 fn handle_inline_fragment<'a, 'b, T>(
-    path: &[String],
+    path: &Path,
     fragment: &InlineFragment<'a, T>,
-    field_queries: &mut Vec<String>,
+    decomposition: &mut Decomposition,
     fragments: &'b BTreeMap<String, &FragmentDefinition<'a, T>>,
+    options: &ParseOptions,
 ) -> anyhow::Result<()>
 where
     T: Text<'a> + Debug,
     T::Value: Display + Debug,
 {
-    let mut path = Vec::from(path);
-    path.push(match &fragment.type_condition {
+    let mut path = path.clone();
+    path.segments.push(match &fragment.type_condition {
         Some(TypeCondition::On(cond)) => format!(
             "... on {} {}",
             cond,
-            directives_to_str(&fragment.directives)
+            directives_to_str(&fragment.directives, options)
         ),
-        None => "".to_string(),
+        None => format!("... {}", directives_to_str(&fragment.directives, options)),
     });
+    path.lines.push(fragment.position.line);
+    path.add_tags(&fragment.directives, options);
+    path.set_endpoint(&fragment.directives, options)?;
+
+    handle_selection_set(
+        &path,
+        &fragment.selection_set,
+        decomposition,
+        fragments,
+        options,
+    )
+}
+
+/// Builds the query for a single field from the segments leading to it. Each
+/// segment carries the field's alias along with its arguments, so aliased
+/// fields keep the response key they had in the original document. If
+/// requested, __typename is selected alongside the next segment in every
+/// selection set but the operation's own.
+fn path_to_query(path: &[String], options: &ParseOptions) -> anyhow::Result<String> {
+    let separator = if options.add_typename {
+        " { __typename "
+    } else {
+        " { "
+    };
+    let query = format!(
+        "{}{}",
+        path.iter()
+            .enumerate()
+            .map(|(i, segment)| match i {
+                0 => segment.clone(),
+                1 => format!(" {{ {}", segment),
+                _ => format!("{}{}", separator, segment),
+            })
+            .join(""),
+        path.iter().skip(1).map(|_| "}").join(" "),
+    );
+    let mut doc = graphql_parser::parse_query::<String>(&query)?;
+    prune_variables(&mut doc);
+    if options.normalize {
+        normalize_document(&mut doc);
+    }
+
+    Ok(format!("{}", doc))
+}
+
+/// Removes the definitions of variables that a query doesn't use, since
+/// servers reject unused variables. Variables used by the operation's own
+/// directives count as used.
+fn prune_variables(doc: &mut Document<'_, String>) {
+    for def in doc.definitions.iter_mut() {
+        if let Definition::Operation(OperationDefinition::Query(query)) = def {
+            let mut used = Vec::new();
+            directive_variables(&query.directives, &mut used);
+            selection_set_variables(&query.selection_set, &mut used);
+            query
+                .variable_definitions
+                .retain(|var| used.contains(&var.name.as_str()));
+        }
+    }
+}
+
+fn selection_set_variables<'a>(ss: &'a SelectionSet<'_, String>, used: &mut Vec<&'a str>) {
+    for item in ss.items.iter() {
+        match item {
+            Selection::Field(field) => {
+                for (_, value) in field.arguments.iter() {
+                    value_variables(value, used);
+                }
+                directive_variables(&field.directives, used);
+                selection_set_variables(&field.selection_set, used);
+            }
+            Selection::InlineFragment(fragment) => {
+                directive_variables(&fragment.directives, used);
+                selection_set_variables(&fragment.selection_set, used);
+            }
+            // Generated queries never contain fragment spreads.
+            Selection::FragmentSpread(_) => {}
+        }
+    }
+}
+
+fn directive_variables<'a>(dirs: &'a [Directive<'_, String>], used: &mut Vec<&'a str>) {
+    for dir in dirs.iter() {
+        for (_, value) in dir.arguments.iter() {
+            value_variables(value, used);
+        }
+    }
+}
+
+fn value_variables<'a>(value: &'a Value<'_, String>, used: &mut Vec<&'a str>) {
+    match value {
+        Value::Variable(name) => used.push(name),
+        Value::List(values) => values.iter().for_each(|value| value_variables(value, used)),
+        Value::Object(fields) => fields
+            .values()
+            .for_each(|value| value_variables(value, used)),
+        _ => {}
+    }
+}
+
+/// Puts a generated query into canonical form: variable definitions and
+/// arguments are sorted by name, and directives that can't change the result,
+/// such as @include(if: true), are dropped. Whitespace is already made
+/// consistent by re-rendering the query.
+fn normalize_document(doc: &mut Document<'_, String>) {
+    for def in doc.definitions.iter_mut() {
+        match def {
+            Definition::Operation(OperationDefinition::Query(query)) => {
+                query
+                    .variable_definitions
+                    .sort_by(|a, b| a.name.cmp(&b.name));
+                normalize_directives(&mut query.directives);
+                normalize_selection_set(&mut query.selection_set);
+            }
+            Definition::Operation(OperationDefinition::SelectionSet(ss)) => {
+                normalize_selection_set(ss)
+            }
+            _ => {}
+        }
+    }
+}
 
-    handle_selection_set(&path, &fragment.selection_set, field_queries, fragments)
+fn normalize_selection_set(ss: &mut SelectionSet<'_, String>) {
+    for item in ss.items.iter_mut() {
+        match item {
+            Selection::Field(field) => {
+                field.arguments.sort_by(|a, b| a.0.cmp(&b.0));
+                normalize_directives(&mut field.directives);
+                normalize_selection_set(&mut field.selection_set);
+            }
+            Selection::InlineFragment(fragment) => {
+                normalize_directives(&mut fragment.directives);
+                normalize_selection_set(&mut fragment.selection_set);
+            }
+            // Generated queries never contain fragment spreads.
+            Selection::FragmentSpread(_) => {}
+        }
+    }
 }
 
-fn path_to_query(path: &[String]) -> anyhow::Result<String> {
-    Ok(format!(
-        "{}",
-        graphql_parser::parse_query::<String>(&format!(
-            "{}{}",
-            path.iter().join(" { "),
-            path.iter().skip(1).map(|_| "}").join(" "),
-        ))?,
-    ))
+fn normalize_directives(dirs: &mut Vec<Directive<'_, String>>) {
+    dirs.retain(|dir| {
+        let redundant = match dir.name.as_str() {
+            "include" => Some(true),
+            "skip" => Some(false),
+            _ => None,
+        };
+        !matches!(
+            (redundant, dir.arguments.as_slice()),
+            (Some(value), [(name, Value::Boolean(b))]) if name == "if" && *b == value
+        )
+    });
+    for dir in dirs.iter_mut() {
+        dir.arguments.sort_by(|a, b| a.0.cmp(&b.0));
+    }
+}
+
+/// Returns the first 8 bytes of the SHA-256 digest of the query, hex encoded.
+fn query_id(query: &str) -> String {
+    Sha256::digest(query.as_bytes())
+        .iter()
+        .take(8)
+        .map(|b| format!("{:02x}", b))
+        .collect()
 }
 
 fn arguments_to_str<'a, T>(args: &[(T::Value, Value<'a, T>)]) -> String
@@ -200,12 +766,17 @@ where
     }
 }
 
-fn directives_to_str<'a, T>(dirs: &[Directive<'a, T>]) -> String
+fn directives_to_str<'a, T>(dirs: &[Directive<'a, T>], options: &ParseOptions) -> String
 where
     T: Text<'a> + Debug,
     T::Value: Display + Debug,
 {
-    dirs.iter().map(|dir| format!("{}", &dir)).join(" ")
+    // Directives that only mean something to us are stripped, since the server
+    // won't know what to do with them.
+    dirs.iter()
+        .filter(|dir| !options.is_internal_directive(dir.name.as_ref()))
+        .map(|dir| format!("{}", &dir))
+        .join(" ")
 }
 
 fn variable_definitions_to_str<'a, T>(defs: &[VariableDefinition<'a, T>]) -> String
@@ -213,5 +784,433 @@ where
     T: Text<'a> + Debug,
     T::Value: Display + Debug,
 {
-    defs.iter().map(|var| format!("{}", &var)).join(", ")
+    if defs.is_empty() {
+        "".to_string()
+    } else {
+        format!(
+            "({})",
+            defs.iter().map(|var| format!("{}", &var)).join(", ")
+        )
+    }
+}
+
+/// Reads field queries to replay, one per line, exactly as given rather than
+/// decomposing them. Since generated queries span several lines, each line may
+/// be a JSON string holding the query, as in the JSON output. Blank lines are
+/// ignored. Lines that can't be parsed are skipped, up to the given number;
+/// beyond that, the first parse error is returned.
+pub(crate) fn replay_queries(raw: &str, max_parse_errors: usize) -> anyhow::Result<Decomposition> {
+    let mut decomposition = Decomposition::default();
+    let mut first_error = None;
+    for (i, line) in raw.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match replay_query(line) {
+            Ok(query) => decomposition.queries.push(query),
+            Err(e) => {
+                decomposition.skipped.push(SkippedQuery {
+                    path: vec![format!("line {}", i + 1)],
+                    // Parse errors span several lines, which would break up
+                    // the list of skipped queries.
+                    reason: format!("{:#}", e)
+                        .lines()
+                        .map(str::trim)
+                        .filter(|line| !line.is_empty())
+                        .join("; "),
+                });
+                first_error.get_or_insert_with(|| {
+                    e.context(format!("parsing the query to replay on line {}", i + 1))
+                });
+            }
+        }
+    }
+
+    match first_error {
+        Some(e) if decomposition.skipped.len() > max_parse_errors => Err(if max_parse_errors > 0 {
+            e.context(format!(
+                "{} queries to replay could not be parsed, more than --max-parse-errors {}",
+                decomposition.skipped.len(),
+                max_parse_errors
+            ))
+        } else {
+            e
+        }),
+        _ => Ok(decomposition),
+    }
+}
+
+fn replay_query(line: &str) -> anyhow::Result<FieldQuery> {
+    let query = match serde_json::from_str::<String>(line) {
+        Ok(query) => query,
+        Err(_) => line.to_string(),
+    };
+    let doc = graphql_parser::parse_query::<&str>(&query)?;
+    let path = doc
+        .definitions
+        .iter()
+        .find_map(|def| match def {
+            Definition::Operation(OperationDefinition::Query(query)) => {
+                Some(replay_path(&query.selection_set))
+            }
+            Definition::Operation(OperationDefinition::SelectionSet(ss)) => Some(replay_path(ss)),
+            _ => None,
+        })
+        .unwrap_or_default();
+    let read_only = !doc.definitions.iter().any(|def| {
+        matches!(
+            def,
+            Definition::Operation(
+                OperationDefinition::Mutation(_) | OperationDefinition::Subscription(_)
+            )
+        )
+    });
+
+    Ok(FieldQuery {
+        id: query_id(&query),
+        query,
+        path,
+        tags: BTreeMap::new(),
+        endpoint: None,
+        lines: Vec::new(),
+        read_only,
+        arg_template: None,
+    })
+}
+
+/// Returns the response keys along the first chain of fields in a selection
+/// set, which for a generated query leads to the field it times.
+fn replay_path<'a, T>(ss: &SelectionSet<'a, T>) -> Vec<String>
+where
+    T: Text<'a>,
+{
+    match ss.items.first() {
+        Some(Selection::Field(field)) => {
+            let key = field.alias.as_ref().unwrap_or(&field.name);
+            std::iter::once(key.as_ref().to_string())
+                .chain(replay_path(&field.selection_set))
+                .collect()
+        }
+        Some(Selection::InlineFragment(fragment)) => replay_path(&fragment.selection_set),
+        Some(Selection::FragmentSpread(_)) | None => Vec::new(),
+    }
+}
+
+/// Prepares the queries for any arguments with overrides to take the value
+/// for each repeat. The queries are parsed once here, since this recurses
+/// once per level of nesting, so that each request only has to fill in the
+/// values.
+pub(crate) fn apply_arg_overrides(
+    queries: &mut [FieldQuery],
+    overrides: &[ArgOverride],
+) -> anyhow::Result<()> {
+    for query in queries.iter_mut() {
+        query.arg_template = arg_template(&query.query, overrides)
+            .with_context(|| format!("overriding the arguments of {}", query.path.join(".")))?;
+    }
+    Ok(())
+}
+
+/// Prefix of the enum values put in place of overridden arguments, followed
+/// by the index of the override, to be cut out of the rendered query.
+const OVERRIDE_PLACEHOLDER: &str = "__graphql_field_timer_override_";
+
+/// Builds the template for a query, or returns None if the query has no
+/// arguments with overrides.
+fn arg_template(query: &str, overrides: &[ArgOverride]) -> anyhow::Result<Option<ArgTemplate>> {
+    let mut doc = graphql_parser::parse_query::<String>(query)?;
+    for def in doc.definitions.iter_mut() {
+        match def {
+            Definition::Operation(OperationDefinition::Query(query)) => {
+                override_selection_set(&mut query.selection_set, overrides)
+            }
+            Definition::Operation(OperationDefinition::SelectionSet(ss)) => {
+                override_selection_set(ss, overrides)
+            }
+            _ => {}
+        }
+    }
+    // An argument that took a variable may no longer use it.
+    prune_variables(&mut doc);
+
+    let rendered = format!("{}", doc);
+    let mut pieces = rendered.split(OVERRIDE_PLACEHOLDER);
+    let mut template = ArgTemplate {
+        parts: vec![pieces.next().unwrap_or_default().to_string()],
+        slots: Vec::new(),
+    };
+    for piece in pieces {
+        let digits = piece.bytes().take_while(u8::is_ascii_digit).count();
+        template
+            .slots
+            .push(overrides[piece[..digits].parse::<usize>()?].clone());
+        template.parts.push(piece[digits..].to_string());
+    }
+
+    Ok((!template.slots.is_empty()).then_some(template))
+}
+
+fn override_selection_set(ss: &mut SelectionSet<'_, String>, overrides: &[ArgOverride]) {
+    for item in ss.items.iter_mut() {
+        match item {
+            Selection::Field(field) => {
+                for (name, value) in field.arguments.iter_mut() {
+                    if let Some(i) = overrides.iter().position(|arg| &arg.name == name) {
+                        *value = Value::Enum(format!("{}{}", OVERRIDE_PLACEHOLDER, i));
+                    }
+                }
+                override_selection_set(&mut field.selection_set, overrides);
+            }
+            Selection::InlineFragment(fragment) => {
+                override_selection_set(&mut fragment.selection_set, overrides)
+            }
+            // Generated queries never contain fragment spreads.
+            Selection::FragmentSpread(_) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options() -> ParseOptions {
+        ParseOptions {
+            add_typename: false,
+            endpoint_directive: String::from("endpoint"),
+            normalize: false,
+            operation_name: None,
+            ignore_directive: String::from("ignore"),
+            tag_directive: String::from("timer"),
+        }
+    }
+
+    fn decompose(raw: &str, options: &ParseOptions) -> Vec<FieldQuery> {
+        let doc = graphql_parser::parse_query::<&str>(raw).unwrap();
+        let decomposition = parse_document(&doc, options).unwrap();
+        assert!(
+            decomposition.skipped.is_empty(),
+            "{:?}",
+            decomposition.skipped
+        );
+        decomposition.queries
+    }
+
+    #[test]
+    fn path_to_query_keeps_aliases() {
+        let query = path_to_query(
+            &[
+                String::from("query "),
+                String::from("me: viewer "),
+                String::from("handle: name "),
+            ],
+            &options(),
+        )
+        .unwrap();
+        assert_eq!(query, "query {\n  me: viewer {\n    handle: name\n  }\n}\n");
+    }
+
+    #[test]
+    fn aliased_siblings_are_distinct() {
+        let queries = decompose(
+            "{ small: avatar(size: 16) large: avatar(size: 256) }",
+            &options(),
+        );
+        assert_eq!(queries.len(), 2);
+        assert_eq!(queries[0].path, ["small"]);
+        assert_eq!(queries[1].path, ["large"]);
+        assert_eq!(queries[0].query, "query {\n  small: avatar(size: 16)\n}\n");
+        assert_eq!(queries[1].query, "query {\n  large: avatar(size: 256)\n}\n");
+        assert_ne!(queries[0].id, queries[1].id);
+    }
+
+    #[test]
+    fn aliased_leaves_beneath_aliased_parents() {
+        let queries = decompose(
+            "{ a: user(id: 1) { n: name } b: user(id: 2) { n: name } }",
+            &options(),
+        );
+        assert_eq!(queries.len(), 2);
+        assert_eq!(queries[0].path, ["a", "n"]);
+        assert_eq!(queries[1].path, ["b", "n"]);
+        assert!(queries[0].query.contains("a: user(id: 1)"));
+        assert!(queries[0].query.contains("n: name"));
+        assert!(queries[1].query.contains("b: user(id: 2)"));
+        assert_ne!(queries[0].id, queries[1].id);
+    }
+
+    #[test]
+    fn typeless_inline_fragment() {
+        let queries = decompose(
+            "{ ... { id } viewer { ... @include(if: true) { name } } }",
+            &options(),
+        );
+        assert_eq!(queries.len(), 2);
+        assert_eq!(queries[0].path, ["id"]);
+        assert_eq!(queries[0].query, "query {\n  ... {\n    id\n  }\n}\n");
+        assert_eq!(queries[1].path, ["viewer", "name"]);
+        assert_eq!(
+            queries[1].query,
+            "query {\n  viewer {\n    ... @include(if: true) {\n      name\n    }\n  }\n}\n"
+        );
+        for query in queries.iter() {
+            graphql_parser::parse_query::<&str>(&query.query).unwrap();
+        }
+    }
+
+    #[test]
+    fn arg_override_cycles_through_range() {
+        let arg: ArgOverride = "page=1..3".parse().unwrap();
+        let values: Vec<_> = (0..5).map(|repeat| arg.value(repeat)).collect();
+        assert_eq!(values, [1, 2, 3, 1, 2]);
+    }
+
+    #[test]
+    fn arg_override_full_range() {
+        let arg: ArgOverride = "n=-2147483648..2147483647".parse().unwrap();
+        assert_eq!(arg.value(0), i32::MIN);
+        assert_eq!(arg.value(1), i32::MIN + 1);
+        // The range has 2^32 values, so the last repeat wraps to its end.
+        assert_eq!(arg.value(usize::MAX), i32::MAX);
+    }
+
+    #[test]
+    fn unused_variables_are_pruned() {
+        let queries = decompose(
+            "query Q($id: ID!, $size: Int, $unused: String) { user(id: $id) { name avatar(size: $size) } }",
+            &options(),
+        );
+        assert_eq!(queries.len(), 2);
+        assert_eq!(
+            queries[0].query,
+            "query Q($id: ID!) {\n  user(id: $id) {\n    name\n  }\n}\n"
+        );
+        assert_eq!(
+            queries[1].query,
+            "query Q($id: ID!, $size: Int) {\n  user(id: $id) {\n    avatar(size: $size)\n  }\n}\n"
+        );
+    }
+
+    #[test]
+    fn operation_directive_variables_are_kept() {
+        let queries = decompose(
+            "query Q($ttl: Int, $live: Boolean, $flag: Boolean, $filter: String) \
+             @cacheControl(maxAge: $ttl) @live(if: $live) \
+             { a b @include(if: $flag) c(where: {name: [$filter]}) }",
+            &options(),
+        );
+        assert_eq!(queries.len(), 3);
+        assert_eq!(
+            queries[0].query,
+            "query Q($ttl: Int, $live: Boolean) @cacheControl(maxAge: $ttl) @live(if: $live) {\n  a\n}\n"
+        );
+        assert!(queries[1]
+            .query
+            .starts_with("query Q($ttl: Int, $live: Boolean, $flag: Boolean) @cacheControl"));
+        assert!(queries[2]
+            .query
+            .starts_with("query Q($ttl: Int, $live: Boolean, $filter: String) @cacheControl"));
+    }
+
+    #[test]
+    fn overridden_variables_are_pruned() {
+        let arg: ArgOverride = "page=1..3".parse().unwrap();
+        let template = arg_template("query Q($page: Int) {\n  list(page: $page)\n}\n", &[arg])
+            .unwrap()
+            .unwrap();
+        assert_eq!(template.render(1), "query Q {\n  list(page: 2)\n}\n");
+    }
+
+    #[test]
+    fn arg_overrides_are_applied_once() {
+        let mut queries = decompose(
+            "{ list(page: 1, size: 10) { items(size: 5) { id } } other }",
+            &options(),
+        );
+        let overrides: Vec<ArgOverride> = vec![
+            "size=20..21".parse().unwrap(),
+            "page=-1..1".parse().unwrap(),
+        ];
+        apply_arg_overrides(&mut queries, &overrides).unwrap();
+
+        let template = queries[0].arg_template.as_ref().unwrap();
+        assert_eq!(
+            template.render(0),
+            "query {\n  list(page: -1, size: 20) {\n    items(size: 20) {\n      id\n    }\n  }\n}\n"
+        );
+        assert_eq!(
+            template.render(3),
+            "query {\n  list(page: -1, size: 21) {\n    items(size: 21) {\n      id\n    }\n  }\n}\n"
+        );
+        // The query itself, and so its id, is unchanged.
+        assert!(queries[0].query.contains("list(page: 1, size: 10)"));
+        assert!(queries[1].arg_template.is_none());
+    }
+
+    #[test]
+    fn add_typename_selects_typename_without_timing_it() {
+        let options = ParseOptions {
+            add_typename: true,
+            ..options()
+        };
+        let queries = decompose("{ viewer { name repo { id } } }", &options);
+        assert_eq!(queries.len(), 2);
+        assert!(queries
+            .iter()
+            .all(|query| query.path.last().unwrap() != "__typename"));
+        assert_eq!(
+            queries[1].query,
+            "query {\n  viewer {\n    __typename\n    repo {\n      __typename\n      id\n    }\n  }\n}\n"
+        );
+    }
+
+    #[test]
+    fn normalize_makes_equivalent_documents_identical() {
+        let options = ParseOptions {
+            normalize: true,
+            ..options()
+        };
+        let a = decompose(
+            "query Q($b: Int, $a: Int) { user(b: $b, a: $a) @include(if: true) { name @skip(if: false) } }",
+            &options,
+        );
+        let b = decompose(
+            "query   Q( $a:Int $b :Int )\n{\n  user( a:$a,\n b:$b ) { name }\n}",
+            &options,
+        );
+        assert_eq!(a[0].query, b[0].query);
+        assert_eq!(a[0].id, b[0].id);
+        assert_eq!(
+            a[0].query,
+            "query Q($a: Int, $b: Int) {\n  user(a: $a, b: $b) {\n    name\n  }\n}\n"
+        );
+    }
+
+    #[test]
+    fn frontmatter_is_blanked() {
+        let (document, variables) =
+            split_frontmatter("---\n{\"id\": 1}\n---\nquery { a }\n").unwrap();
+        assert_eq!(variables.as_deref(), Some("{\"id\": 1}\n"));
+        // Line numbers are kept, so the query is still on the fourth line.
+        assert_eq!(document, "\n\n\nquery { a }\n");
+    }
+
+    #[test]
+    fn variables_comment() {
+        let raw = "# timing case\n#  variables: {\"id\": 1}\nquery { a }\n";
+        let (document, variables) = split_frontmatter(raw).unwrap();
+        assert_eq!(variables.as_deref(), Some("{\"id\": 1}"));
+        assert_eq!(document, raw);
+    }
+
+    #[test]
+    fn variables_comment_after_query_is_ignored() {
+        let (_, variables) = split_frontmatter("query { a }\n# variables: {}\n").unwrap();
+        assert_eq!(variables, None);
+    }
+
+    #[test]
+    fn unterminated_frontmatter() {
+        assert!(split_frontmatter("---\n{}\nquery { a }\n").is_err());
+    }
 }
@@ -0,0 +1,235 @@
+//! A minimal connection pool, allowing connections to be reused between
+//! queries and capping how many are open to each host.
+//!
+//! An HTTP/1.1 connection is checked out for the whole of a request, and isn't
+//! checked back in until the response body has been read, so requests are
+//! never pipelined on it and each timing covers a single round trip. An HTTP/2
+//! connection is shared instead: every request to the host is sent on it as a
+//! stream of its own, as a browser would.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
+use futures_util::future::poll_fn;
+use hyper::{
+    client::conn::{ResponseFuture, SendRequest},
+    Body, Request,
+};
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
+
+type Key = (String, u16);
+
+#[derive(Debug)]
+pub(crate) struct Pool {
+    hosts: Mutex<HashMap<Key, Host>>,
+    max_per_host: Option<usize>,
+    // Identifies each connection opened, so that a closed shared connection
+    // can be told apart from the one that replaced it.
+    next_id: AtomicUsize,
+}
+
+/// The HTTP version spoken on a connection.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Protocol {
+    Http1,
+    /// Negotiated through ALPN. Requests must carry an absolute URI, since
+    /// the scheme and authority are sent as pseudo-headers.
+    Http2,
+}
+
+#[derive(Debug)]
+struct Host {
+    idle: Vec<Open>,
+    // The HTTP/2 connection that requests are multiplexed on, if there is one.
+    shared: Option<Open>,
+    // Limits the number of open connections. Each connection holds a permit
+    // until it's closed or discarded.
+    permits: Option<Arc<Semaphore>>,
+    // Woken when a connection is checked in or shared, for requests waiting
+    // for one because no more can be opened.
+    available: Arc<Notify>,
+}
+
+/// An open connection, along with its claim on the host's permits. The sender
+/// is only locked while a request is handed to it, not while waiting for the
+/// response, so an HTTP/2 connection can carry many requests at once.
+#[derive(Debug, Clone)]
+struct Open {
+    id: usize,
+    sender: Arc<Mutex<SendRequest<Body>>>,
+    protocol: Protocol,
+    permit: Option<Arc<OwnedSemaphorePermit>>,
+}
+
+/// A connection checked out of the pool. It should be checked back in once
+/// the response body has been read, so that it can be reused.
+#[derive(Debug)]
+pub(crate) struct Connection {
+    id: usize,
+    key: Key,
+    pub(crate) protocol: Protocol,
+    /// True if the connection was already open, rather than newly opened.
+    pub(crate) reused: bool,
+    /// When the connection became available for the request, after any wait
+    /// for one, and before any time spent opening it.
+    pub(crate) available: Instant,
+    sender: Arc<Mutex<SendRequest<Body>>>,
+    permit: Option<Arc<OwnedSemaphorePermit>>,
+}
+
+impl Connection {
+    /// Sends a request on the connection, returning a future that resolves
+    /// to the response.
+    pub(crate) fn send_request(&self, request: Request<Body>) -> ResponseFuture {
+        self.sender.lock().unwrap().send_request(request)
+    }
+}
+
+/// Waits until the connection can take another request, failing if it has
+/// been closed.
+async fn ready(sender: &Mutex<SendRequest<Body>>) -> hyper::Result<()> {
+    poll_fn(|cx| sender.lock().unwrap().poll_ready(cx)).await
+}
+
+impl Pool {
+    pub(crate) fn new(max_per_host: Option<usize>) -> Self {
+        Self {
+            hosts: Mutex::new(HashMap::new()),
+            max_per_host,
+            next_id: AtomicUsize::new(0),
+        }
+    }
+
+    /// Checks out a connection to the given host: the shared HTTP/2
+    /// connection or an idle one if there is one, or else a new one opened
+    /// with the given function. If the number of connections to the host is
+    /// capped and reached, this waits until one is checked in.
+    pub(crate) async fn checkout<F, Fut>(
+        &self,
+        host: &str,
+        port: u16,
+        connect: F,
+    ) -> anyhow::Result<Connection>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = anyhow::Result<(SendRequest<Body>, Protocol)>>,
+    {
+        let key = (host.to_string(), port);
+        let (permits, available) = self.host(&key);
+
+        let permit = loop {
+            // Prefer a connection that's already open, discarding any that
+            // have since been closed.
+            while let Some(open) = self.reusable(&key) {
+                if ready(&open.sender).await.is_ok() {
+                    return Ok(Connection {
+                        id: open.id,
+                        key,
+                        protocol: open.protocol,
+                        reused: true,
+                        available: Instant::now(),
+                        sender: open.sender,
+                        permit: open.permit,
+                    });
+                }
+                self.discard_shared(&key, &open);
+            }
+
+            let permits = match &permits {
+                Some(permits) => permits.clone(),
+                None => break None,
+            };
+            // Registered before trying for a permit, so that a connection
+            // checked in meanwhile isn't missed.
+            let notified = available.notified();
+            tokio::select! {
+                permit = permits.acquire_owned() => break Some(Arc::new(permit?)),
+                _ = notified => continue,
+            }
+        };
+
+        let available = Instant::now();
+        let (sender, protocol) = connect().await?;
+        let open = Open {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            sender: Arc::new(Mutex::new(sender)),
+            protocol,
+            permit,
+        };
+        if protocol == Protocol::Http2 {
+            let mut hosts = self.hosts.lock().unwrap();
+            if let Some(host) = hosts.get_mut(&key) {
+                if host.shared.is_none() {
+                    host.shared = Some(open.clone());
+                    host.available.notify_waiters();
+                }
+            }
+        }
+        Ok(Connection {
+            id: open.id,
+            key,
+            protocol: open.protocol,
+            reused: false,
+            available,
+            sender: open.sender,
+            permit: open.permit,
+        })
+    }
+
+    /// Returns a connection to the pool for reuse. An HTTP/2 connection is
+    /// already shared, so there's nothing to return.
+    pub(crate) fn checkin(&self, connection: Connection) {
+        if connection.protocol == Protocol::Http2 {
+            return;
+        }
+        let mut hosts = self.hosts.lock().unwrap();
+        if let Some(host) = hosts.get_mut(&connection.key) {
+            host.idle.push(Open {
+                id: connection.id,
+                sender: connection.sender,
+                protocol: connection.protocol,
+                permit: connection.permit,
+            });
+            host.available.notify_one();
+        }
+    }
+
+    fn host(&self, key: &Key) -> (Option<Arc<Semaphore>>, Arc<Notify>) {
+        let mut hosts = self.hosts.lock().unwrap();
+        let host = hosts.entry(key.clone()).or_insert_with(|| Host {
+            idle: Vec::new(),
+            shared: None,
+            permits: self.max_per_host.map(|max| Arc::new(Semaphore::new(max))),
+            available: Arc::new(Notify::new()),
+        });
+        (host.permits.clone(), host.available.clone())
+    }
+
+    fn reusable(&self, key: &Key) -> Option<Open> {
+        let mut hosts = self.hosts.lock().unwrap();
+        let host = hosts.get_mut(key)?;
+        match &host.shared {
+            Some(shared) => Some(shared.clone()),
+            None => host.idle.pop(),
+        }
+    }
+
+    /// Stops sharing a closed HTTP/2 connection, unless it has already been
+    /// replaced.
+    fn discard_shared(&self, key: &Key, open: &Open) {
+        let mut hosts = self.hosts.lock().unwrap();
+        if let Some(host) = hosts.get_mut(key) {
+            let same = host.shared.as_ref().map(|shared| shared.id) == Some(open.id);
+            if same {
+                host.shared = None;
+            }
+        }
+    }
+}
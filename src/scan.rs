@@ -0,0 +1,196 @@
+//! Locating the top-level members of a JSON response body without parsing it,
+//! so that large responses can be classified without the cost of building a
+//! `serde_json::Value` for their data.
+
+use std::ops::Range;
+
+/// Where the top-level data and errors of a response are in its body. A
+/// member that is absent or null is None.
+#[derive(Debug, Default)]
+pub(crate) struct Members {
+    pub(crate) data: Option<Range<usize>>,
+    pub(crate) errors: Option<Range<usize>>,
+}
+
+/// Scans a JSON object for its top-level data and errors members. Nested
+/// values are skipped over by matching brackets and strings, and aren't
+/// otherwise validated.
+pub(crate) fn members(body: &[u8]) -> anyhow::Result<Members> {
+    let mut scanner = Scanner { body, pos: 0 };
+    let mut members = Members::default();
+
+    scanner.expect(b'{')?;
+    if scanner.peek() == Some(b'}') {
+        return Ok(members);
+    }
+    loop {
+        let key = scanner.string()?;
+        scanner.expect(b':')?;
+        scanner.skip_whitespace();
+        let start = scanner.pos;
+        scanner.value()?;
+        let value = start..scanner.pos;
+
+        if &body[value.clone()] != b"null" {
+            match key {
+                b"data" => members.data = Some(value),
+                b"errors" => members.errors = Some(value),
+                _ => {}
+            }
+        }
+        match scanner.next() {
+            Some(b',') => scanner.skip_whitespace(),
+            Some(b'}') => return Ok(members),
+            _ => return Err(scanner.error("expected , or }")),
+        }
+    }
+}
+
+struct Scanner<'a> {
+    body: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Scanner<'a> {
+    fn skip_whitespace(&mut self) {
+        while self.body.get(self.pos).is_some_and(u8::is_ascii_whitespace) {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        self.skip_whitespace();
+        self.body.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<u8> {
+        let b = self.peek()?;
+        self.pos += 1;
+        Some(b)
+    }
+
+    fn expect(&mut self, expected: u8) -> anyhow::Result<()> {
+        match self.next() {
+            Some(b) if b == expected => Ok(()),
+            _ => Err(self.error(&format!("expected {}", expected as char))),
+        }
+    }
+
+    /// Reads a string, returning its contents with any escapes left as is.
+    fn string(&mut self) -> anyhow::Result<&'a [u8]> {
+        self.expect(b'"')?;
+        let start = self.pos;
+        while let Some(b) = self.body.get(self.pos) {
+            match b {
+                b'"' => {
+                    self.pos += 1;
+                    return Ok(&self.body[start..self.pos - 1]);
+                }
+                b'\\' => self.pos += 2,
+                _ => self.pos += 1,
+            }
+        }
+        Err(self.error("unterminated string"))
+    }
+
+    /// Skips a value, leaving the position just after it.
+    fn value(&mut self) -> anyhow::Result<()> {
+        match self.peek() {
+            Some(b'"') => self.string().map(|_| ()),
+            Some(b'{' | b'[') => {
+                let mut depth = 0;
+                loop {
+                    match self.body.get(self.pos) {
+                        Some(b'"') => {
+                            self.string()?;
+                            continue;
+                        }
+                        Some(b'{' | b'[') => depth += 1,
+                        Some(b'}' | b']') => {
+                            depth -= 1;
+                            if depth == 0 {
+                                self.pos += 1;
+                                return Ok(());
+                            }
+                        }
+                        Some(_) => {}
+                        None => return Err(self.error("unterminated value")),
+                    }
+                    self.pos += 1;
+                }
+            }
+            Some(_) => {
+                let start = self.pos;
+                while self
+                    .body
+                    .get(self.pos)
+                    .is_some_and(|b| !matches!(b, b',' | b'}' | b']') && !b.is_ascii_whitespace())
+                {
+                    self.pos += 1;
+                }
+                if self.pos == start {
+                    return Err(self.error("expected a value"));
+                }
+                Ok(())
+            }
+            None => Err(self.error("expected a value")),
+        }
+    }
+
+    fn error(&self, message: &str) -> anyhow::Error {
+        anyhow::anyhow!("{} at byte {} of the response", message, self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn member<'a>(body: &'a [u8], range: &Option<Range<usize>>) -> Option<&'a [u8]> {
+        range.clone().map(|range| &body[range])
+    }
+
+    #[test]
+    fn finds_data_and_errors() {
+        let body = br#" { "extensions": {"a": [1, {"b": "}"}]}, "data" : {"x": "\"}]"},
+            "errors": [{"message": "m"}] } "#;
+        let found = members(body).unwrap();
+        assert_eq!(member(body, &found.data), Some(&br#"{"x": "\"}]"}"#[..]));
+        assert_eq!(
+            member(body, &found.errors),
+            Some(&br#"[{"message": "m"}]"#[..])
+        );
+    }
+
+    #[test]
+    fn null_and_absent_members_are_none() {
+        let found = members(br#"{"data": null, "extensions": 1}"#).unwrap();
+        assert!(found.data.is_none());
+        assert!(found.errors.is_none());
+
+        let found = members(b"{}").unwrap();
+        assert!(found.data.is_none());
+    }
+
+    #[test]
+    fn scalar_data() {
+        let body = br#"{"data":true,"errors":[]}"#;
+        let found = members(body).unwrap();
+        assert_eq!(member(body, &found.data), Some(&b"true"[..]));
+        assert_eq!(member(body, &found.errors), Some(&b"[]"[..]));
+    }
+
+    #[test]
+    fn malformed_bodies() {
+        for body in [
+            &b""[..],
+            b"[]",
+            br#"{"data": {"a": 1}"#,
+            br#"{"data": "unterminated}"#,
+            br#"{"data": }"#,
+            br#"{"data": 1 "errors": []}"#,
+        ] {
+            assert!(members(body).is_err(), "{}", String::from_utf8_lossy(body));
+        }
+    }
+}
@@ -0,0 +1,92 @@
+//! Appending results to a SQLite database, so that latencies can be tracked
+//! across runs. The statements are run with the sqlite3 command line tool,
+//! which must be on the PATH.
+
+use std::{
+    io::Write,
+    path::Path,
+    process::{Command, Stdio},
+    time::SystemTime,
+};
+
+use anyhow::Context;
+
+use crate::timer::{self, Result, Status};
+
+const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS results (
+    run_started TEXT NOT NULL,
+    query_id TEXT NOT NULL,
+    path TEXT NOT NULL,
+    duration REAL NOT NULL,
+    status TEXT NOT NULL,
+    status_code INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS results_query_id ON results (query_id, run_started);
+";
+
+/// Appends a row for each result to the results table, creating it if
+/// needed. Every row from the run shares the same start time.
+pub(crate) fn append(
+    path: &Path,
+    run_started: SystemTime,
+    results: &[Result],
+) -> anyhow::Result<()> {
+    let run_started = timer::format_timestamp(run_started);
+    let mut script = format!("{}BEGIN;\n", SCHEMA);
+    for result in results.iter() {
+        script.push_str(&format!(
+            "INSERT INTO results VALUES ({}, {}, {}, {}, {}, {});\n",
+            quote(&run_started),
+            quote(&result.query.id),
+            quote(&result.query.path.join(".")),
+            result.duration.as_secs_f64(),
+            quote(match result.status {
+                Status::Success => "success",
+                Status::Failure => "failure",
+            }),
+            result.status_code
+        ));
+    }
+    script.push_str("COMMIT;\n");
+
+    let mut child = Command::new("sqlite3")
+        .arg("-bail")
+        .arg(path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("running sqlite3; is it installed?")?;
+    child
+        .stdin
+        .take()
+        .expect("stdin is piped")
+        .write_all(script.as_bytes())?;
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "writing results to {} failed: {}",
+            path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(())
+}
+
+/// Quotes a value as a SQL string literal.
+fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quote_doubles_single_quotes() {
+        assert_eq!(quote("user.name"), "'user.name'");
+        assert_eq!(quote("o'brien.friends"), "'o''brien.friends'");
+        assert_eq!(quote("'"), "''''");
+    }
+}
@@ -0,0 +1,306 @@
+//! An interactive table for exploring results, as an alternative to the flat
+//! listing of the human output. Results can be sorted and filtered, expanded
+//! to show their response, and re-run one at a time.
+
+use std::cmp::Ordering;
+
+use console::{style, truncate_str, Key, Term};
+
+use crate::{
+    output::{render_error_code, render_status},
+    timer::{Result, Status, Timer},
+};
+
+/// What the rows are ordered by. Durations are slowest first, as the slow
+/// fields are usually what's being looked for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SortBy {
+    Duration,
+    Path,
+    Status,
+}
+
+impl SortBy {
+    fn next(self) -> Self {
+        match self {
+            Self::Duration => Self::Path,
+            Self::Path => Self::Status,
+            Self::Status => Self::Duration,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Duration => "duration",
+            Self::Path => "path",
+            Self::Status => "status",
+        }
+    }
+}
+
+/// Which rows are shown, by status.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Filter {
+    All,
+    Ok,
+    Failed,
+}
+
+impl Filter {
+    fn next(self) -> Self {
+        match self {
+            Self::All => Self::Ok,
+            Self::Ok => Self::Failed,
+            Self::Failed => Self::All,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::All => "all",
+            Self::Ok => "ok",
+            Self::Failed => "failed",
+        }
+    }
+
+    fn matches(self, status: Status) -> bool {
+        match self {
+            Self::All => true,
+            Self::Ok => status == Status::Success,
+            Self::Failed => status == Status::Failure,
+        }
+    }
+}
+
+const HELP: &str = "↑/↓ move  enter expand  s sort  f filter  r re-run  q quit";
+
+/// A result as shown in the table. A re-run replaces the result shown, but
+/// not the one recorded for the run, so the summary still reflects what was
+/// timed.
+struct Row<'a> {
+    result: &'a Result,
+    rerun: Option<Result>,
+}
+
+impl Row<'_> {
+    fn current(&self) -> &Result {
+        self.rerun.as_ref().unwrap_or(self.result)
+    }
+}
+
+struct Explorer<'a> {
+    rows: Vec<Row<'a>>,
+    // Indices into rows of those shown, in display order.
+    visible: Vec<usize>,
+    selected: usize,
+    expanded: bool,
+    sort: SortBy,
+    filter: Filter,
+    message: Option<String>,
+}
+
+impl<'a> Explorer<'a> {
+    fn new(results: &'a [Result]) -> Self {
+        let mut explorer = Self {
+            rows: results
+                .iter()
+                .map(|result| Row {
+                    result,
+                    rerun: None,
+                })
+                .collect(),
+            visible: Vec::new(),
+            selected: 0,
+            expanded: false,
+            sort: SortBy::Duration,
+            filter: Filter::All,
+            message: None,
+        };
+        explorer.refresh();
+        explorer
+    }
+
+    /// Recomputes the visible rows after the sort or filter changes, keeping
+    /// the same row selected where it's still shown.
+    fn refresh(&mut self) {
+        let selected = self.visible.get(self.selected).copied();
+        let rows = &self.rows;
+        let mut visible: Vec<usize> = (0..rows.len())
+            .filter(|i| self.filter.matches(rows[*i].current().status))
+            .collect();
+        visible.sort_by(|a, b| {
+            let (a, b) = (rows[*a].current(), rows[*b].current());
+            match self.sort {
+                SortBy::Duration => b.duration.cmp(&a.duration),
+                SortBy::Path => a.query.path.cmp(&b.query.path),
+                SortBy::Status => match (a.status, b.status) {
+                    (Status::Failure, Status::Success) => Ordering::Less,
+                    (Status::Success, Status::Failure) => Ordering::Greater,
+                    _ => b.duration.cmp(&a.duration),
+                },
+            }
+        });
+        self.selected = selected
+            .and_then(|selected| visible.iter().position(|i| *i == selected))
+            .unwrap_or(0);
+        self.visible = visible;
+    }
+
+    fn selected_row(&self) -> Option<usize> {
+        self.visible.get(self.selected).copied()
+    }
+
+    fn draw(&self, term: &Term) -> anyhow::Result<()> {
+        let (height, width) = term.size();
+        let (height, width) = (height as usize, width as usize);
+        let mut lines = vec![
+            format!(
+                "{} of {} field queries, sorted by {}, showing {}",
+                self.visible.len(),
+                self.rows.len(),
+                style(self.sort.name()).bold(),
+                style(self.filter.name()).bold(),
+            ),
+            style(HELP).dim().to_string(),
+        ];
+
+        let detail = match (self.expanded, self.selected_row()) {
+            (true, Some(i)) => detail_lines(self.rows[i].current()),
+            _ => Vec::new(),
+        };
+        // Leave room for the header, the message line, and any detail.
+        let room = height
+            .saturating_sub(lines.len() + 1)
+            .saturating_sub(detail.len().min(height / 2))
+            .max(1);
+        let first = (self.selected + 1).saturating_sub(room);
+
+        for (position, i) in self.visible.iter().enumerate().skip(first).take(room) {
+            let row = &self.rows[*i];
+            let result = row.current();
+            let line = format!(
+                "{}{} {:>8.3}s {}{}",
+                render_status(result.status),
+                match result.error_code.as_deref() {
+                    Some(code) if result.status == Status::Failure => {
+                        format!(" {}", render_error_code(code))
+                    }
+                    _ => String::new(),
+                },
+                result.duration.as_secs_f64(),
+                result.query.path.join("."),
+                if row.rerun.is_some() {
+                    style(" (re-run)").dim().to_string()
+                } else {
+                    String::new()
+                },
+            );
+            let line = truncate_str(&line, width, "…").into_owned();
+            if position == self.selected {
+                lines.push(style(line).reverse().to_string());
+                if self.expanded {
+                    lines.extend(
+                        detail
+                            .iter()
+                            .take(height / 2)
+                            .map(|line| truncate_str(line, width, "…").into_owned()),
+                    );
+                }
+            } else {
+                lines.push(line);
+            }
+        }
+        if let Some(message) = &self.message {
+            lines.push(style(message).yellow().to_string());
+        }
+
+        term.clear_screen()?;
+        term.write_str(&lines.join("\n"))?;
+        Ok(())
+    }
+}
+
+/// The query and response of a result, indented beneath its row.
+fn detail_lines(result: &Result) -> Vec<String> {
+    let mut lines: Vec<String> = result
+        .query
+        .query
+        .lines()
+        .map(|line| format!("    {}", line))
+        .collect();
+    if let Some(explanation) = &result.explanation {
+        lines.push(format!("    {}", style(explanation).dim()));
+    }
+    let response = style(result.dump_response());
+    lines.push(format!(
+        "    {}",
+        match result.status {
+            Status::Success => response.dim(),
+            Status::Failure => response.red(),
+        }
+    ));
+    lines
+}
+
+/// Shows the results in an interactive table until the user quits. Re-runs
+/// are sent with the same timer, but aren't added to the results.
+pub(crate) async fn explore(timer: &Timer, results: &[Result]) -> anyhow::Result<()> {
+    let term = Term::stdout();
+    if !term.is_term() {
+        anyhow::bail!("--tui needs standard output to be a terminal");
+    }
+
+    let mut explorer = Explorer::new(results);
+    term.hide_cursor()?;
+    let outcome = run(timer, &term, &mut explorer).await;
+    term.clear_screen()?;
+    term.show_cursor()?;
+    outcome
+}
+
+async fn run(timer: &Timer, term: &Term, explorer: &mut Explorer<'_>) -> anyhow::Result<()> {
+    loop {
+        explorer.draw(term)?;
+        let key = term.read_key()?;
+        explorer.message = None;
+        match key {
+            Key::ArrowUp | Key::Char('k') => {
+                explorer.selected = explorer.selected.saturating_sub(1);
+            }
+            Key::ArrowDown | Key::Char('j') if explorer.selected + 1 < explorer.visible.len() => {
+                explorer.selected += 1;
+            }
+            Key::Home => explorer.selected = 0,
+            Key::End => explorer.selected = explorer.visible.len().saturating_sub(1),
+            Key::Enter | Key::Char(' ') => explorer.expanded = !explorer.expanded,
+            Key::Char('s') => {
+                explorer.sort = explorer.sort.next();
+                explorer.refresh();
+            }
+            Key::Char('f') => {
+                explorer.filter = explorer.filter.next();
+                explorer.refresh();
+            }
+            Key::Char('r') => {
+                if let Some(i) = explorer.selected_row() {
+                    explorer.message = Some(format!(
+                        "re-running {}…",
+                        explorer.rows[i].result.query.path.join(".")
+                    ));
+                    explorer.draw(term)?;
+                    let result = timer.send(&explorer.rows[i].result.query, 0).await?;
+                    explorer.message = Some(format!(
+                        "{} took {:.3}s (was {:.3}s)",
+                        result.query.path.join("."),
+                        result.duration.as_secs_f64(),
+                        explorer.rows[i].result.duration.as_secs_f64()
+                    ));
+                    explorer.rows[i].rerun = Some(result);
+                    explorer.refresh();
+                }
+            }
+            Key::Char('q') | Key::Escape => return Ok(()),
+            _ => {}
+        }
+    }
+}
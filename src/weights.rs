@@ -0,0 +1,218 @@
+//! Relative weights for field queries, so that the queries can be sent in
+//! proportion to how often they are requested in production rather than
+//! uniformly.
+
+use std::{collections::BTreeMap, path::Path};
+
+use anyhow::Context;
+use rand::{distributions::WeightedIndex, prelude::Distribution, seq::SliceRandom};
+
+use crate::parser::FieldQuery;
+
+/// Weights keyed by dotted field path, such as `user.friends.name`. A path
+/// also covers every field beneath it, with the longest matching path taking
+/// precedence. Fields that no path covers have a weight of 1.
+#[derive(Debug)]
+pub(crate) struct Weights {
+    paths: BTreeMap<String, f64>,
+}
+
+impl Weights {
+    /// Loads weights from a JSON (or JSON5) object mapping paths to numbers.
+    pub(crate) fn load(path: &Path) -> anyhow::Result<Self> {
+        let raw = std::fs::read_to_string(path)
+            .with_context(|| format!("reading weights file {}", path.display()))?;
+        let paths: BTreeMap<String, f64> = json5::from_str(&raw)
+            .with_context(|| format!("parsing weights file {}", path.display()))?;
+        if let Some((path, weight)) = paths
+            .iter()
+            .find(|(_, weight)| !weight.is_finite() || **weight < 0.0)
+        {
+            anyhow::bail!(
+                "weight for {} must be a finite, non-negative number: {}",
+                path,
+                weight
+            );
+        }
+
+        Ok(Self { paths })
+    }
+
+    fn weight(&self, query: &FieldQuery) -> f64 {
+        (0..=query.path.len())
+            .rev()
+            .find_map(|len| self.paths.get(&query.path[..len].join(".")))
+            .copied()
+            .unwrap_or(1.0)
+    }
+
+    /// Builds a schedule of the given number of queries, dividing them between
+    /// the queries in proportion to their weights and shuffling the result.
+    /// Each query is paired with the index of the repeat it belongs to.
+    pub(crate) fn schedule<'a>(
+        &self,
+        queries: &'a [FieldQuery],
+        total: usize,
+    ) -> anyhow::Result<Vec<(usize, &'a FieldQuery)>> {
+        if queries.is_empty() {
+            return Ok(Vec::new());
+        }
+        let weights: Vec<f64> = queries.iter().map(|query| self.weight(query)).collect();
+        let sum: f64 = weights.iter().sum();
+        if sum <= 0.0 {
+            anyhow::bail!("every field query has a weight of zero");
+        }
+
+        // Apportion by largest remainder, so the counts add up to the total.
+        let shares: Vec<f64> = weights.iter().map(|w| w / sum * total as f64).collect();
+        let mut counts: Vec<usize> = shares.iter().map(|share| share.floor() as usize).collect();
+        let remaining = total - counts.iter().sum::<usize>();
+        let mut by_remainder: Vec<usize> = (0..queries.len()).collect();
+        by_remainder.sort_by(|a, b| {
+            (shares[*b] - shares[*b].floor()).total_cmp(&(shares[*a] - shares[*a].floor()))
+        });
+        for i in by_remainder.into_iter().take(remaining) {
+            counts[i] += 1;
+        }
+
+        let mut schedule: Vec<(usize, &FieldQuery)> = queries
+            .iter()
+            .zip(counts)
+            .flat_map(|(query, count)| (0..count).map(move |i| (i, query)))
+            .collect();
+        schedule.shuffle(&mut rand::thread_rng());
+        Ok(schedule)
+    }
+
+    /// Samples the queries endlessly in proportion to their weights. Each
+    /// query is paired with the number of times it has been sampled before.
+    pub(crate) fn sample<'a>(
+        &self,
+        queries: &'a [FieldQuery],
+    ) -> anyhow::Result<impl Iterator<Item = (usize, &'a FieldQuery)>> {
+        let index = WeightedIndex::new(queries.iter().map(|query| self.weight(query)))
+            .context("cannot sample the field queries by weight")?;
+        let mut counts = vec![0; queries.len()];
+        let mut rng = rand::thread_rng();
+
+        Ok(std::iter::from_fn(move || {
+            let i = index.sample(&mut rng);
+            counts[i] += 1;
+            Some((counts[i] - 1, &queries[i]))
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field_query(path: &str) -> FieldQuery {
+        FieldQuery {
+            id: path.to_string(),
+            query: format!("{{ {} }}", path),
+            path: path.split('.').map(String::from).collect(),
+            tags: Default::default(),
+            endpoint: None,
+            lines: Vec::new(),
+            read_only: true,
+            arg_template: None,
+        }
+    }
+
+    fn with_paths(paths: &[(&str, f64)]) -> Weights {
+        Weights {
+            paths: paths
+                .iter()
+                .map(|(path, weight)| (path.to_string(), *weight))
+                .collect(),
+        }
+    }
+
+    /// Returns how many times each query appears in a schedule.
+    fn counts(schedule: &[(usize, &FieldQuery)], queries: &[FieldQuery]) -> Vec<usize> {
+        queries
+            .iter()
+            .map(|query| {
+                schedule
+                    .iter()
+                    .filter(|(_, scheduled)| scheduled.id == query.id)
+                    .count()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn longest_prefix_wins() {
+        let weights = with_paths(&[
+            ("user", 2.0),
+            ("user.friends", 5.0),
+            ("user.friends.name", 0.5),
+        ]);
+        assert_eq!(weights.weight(&field_query("user.friends.name")), 0.5);
+        assert_eq!(weights.weight(&field_query("user.friends.id")), 5.0);
+        assert_eq!(weights.weight(&field_query("user.id")), 2.0);
+        // Only whole segments match.
+        assert_eq!(weights.weight(&field_query("username")), 1.0);
+    }
+
+    #[test]
+    fn schedule_apportions_by_largest_remainder() {
+        let queries = [field_query("a"), field_query("b"), field_query("c")];
+        let weights = with_paths(&[("a", 1.0), ("b", 1.0), ("c", 1.0)]);
+        for total in [0, 1, 2, 10, 11] {
+            let schedule = weights.schedule(&queries, total).unwrap();
+            let counts = counts(&schedule, &queries);
+            assert_eq!(counts.iter().sum::<usize>(), total);
+            assert!(counts.iter().max().unwrap() - counts.iter().min().unwrap() <= 1);
+        }
+
+        let weights = with_paths(&[("a", 6.0), ("b", 3.0), ("c", 1.0)]);
+        let schedule = weights.schedule(&queries, 7).unwrap();
+        // Shares of 4.2, 2.1 and 0.7 round to 4, 2 and 1.
+        assert_eq!(counts(&schedule, &queries), [4, 2, 1]);
+        // Each query's repeats are numbered from zero.
+        let mut repeats: Vec<usize> = schedule
+            .iter()
+            .filter(|(_, query)| query.id == "a")
+            .map(|(repeat, _)| *repeat)
+            .collect();
+        repeats.sort();
+        assert_eq!(repeats, [0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn schedule_requires_a_nonzero_weight() {
+        let queries = [field_query("a")];
+        assert!(with_paths(&[("a", 0.0)]).schedule(&queries, 3).is_err());
+    }
+
+    #[test]
+    fn load_rejects_non_finite_weights() {
+        let path = std::env::temp_dir().join(format!(
+            "graphql-field-timer-weights-{}.json5",
+            std::process::id()
+        ));
+        for (raw, valid) in [
+            ("{a: 2, b: 0}", true),
+            ("{a: NaN}", false),
+            ("{a: Infinity}", false),
+            ("{a: -1}", false),
+        ] {
+            std::fs::write(&path, raw).unwrap();
+            match Weights::load(&path) {
+                Ok(_) => assert!(valid, "{}", raw),
+                Err(e) => {
+                    assert!(!valid, "{}: {:#}", raw, e);
+                    assert!(
+                        e.to_string()
+                            .contains("must be a finite, non-negative number"),
+                        "{}",
+                        e
+                    );
+                }
+            }
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,90 @@
+use std::{
+    io::{Read, Write},
+    net::TcpListener,
+    process::{Command, Stdio},
+};
+
+/// Serves every request on a local port with the same successful response,
+/// and returns the URL to send requests to.
+fn serve() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/graphql", listener.local_addr().unwrap());
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            std::thread::spawn(move || {
+                let body = r#"{"data":{"a":1}}"#;
+                let mut request = Vec::new();
+                let mut buf = [0; 4096];
+                loop {
+                    let n = match stream.read(&mut buf) {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => n,
+                    };
+                    request.extend_from_slice(&buf[..n]);
+                    // Each request is read up to the end of its headers, and
+                    // then its body, which has a Content-Length.
+                    while let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                        let head = String::from_utf8_lossy(&request[..end]).to_ascii_lowercase();
+                        let length: usize = head
+                            .lines()
+                            .find_map(|line| line.strip_prefix("content-length:"))
+                            .map(|value| value.trim().parse().unwrap())
+                            .unwrap_or_default();
+                        if request.len() < end + 4 + length {
+                            break;
+                        }
+                        request.drain(..end + 4 + length);
+                        write!(
+                            stream,
+                            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                            body.len(),
+                            body
+                        )
+                        .unwrap();
+                    }
+                }
+            });
+        }
+    });
+    url
+}
+
+#[test]
+fn json_on_stdout_has_no_progress_bytes() {
+    let url = serve();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_graphql-field-timer"))
+        .args([
+            "--url",
+            &url,
+            "--format",
+            "json",
+            "--progress-stream",
+            "stderr",
+        ])
+        // Force the progress bar and colours on, as if run from a terminal.
+        .arg("--interactive")
+        .arg("--repeat=3")
+        .env_remove("CI")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(b"{ a b }").unwrap();
+    let output = child.wait_with_output().unwrap();
+
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(
+        !output.stdout.iter().any(|b| *b == b'\x1b' || *b == b'\r'),
+        "{:?}",
+        String::from_utf8_lossy(&output.stdout)
+    );
+    // One result for each field, covering all of its repeats.
+    let results: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(results.as_array().map(Vec::len), Some(2), "{}", results);
+}